@@ -21,7 +21,7 @@ use std::ops::Deref; // we use this but rustc doesn't know
 use quick_error::quick_error;
 
 use seckey::SecKey;
-use sodiumoxide::crypto::{box_, sealedbox};
+use sodiumoxide::crypto::{box_, generichash, sealedbox};
 use serde::{Serialize, Deserialize};
 use serde_cbor;
 
@@ -125,6 +125,11 @@ impl Certificate {
         pk!(self).as_ref().to_owned()
     }
 
+    /// The public part of the certificate
+    pub fn public_certificate(&self) -> PublicCertificate {
+        PublicCertificate{public_key: *pk!(self)}
+    }
+
     /// Clone the bytes of the private key
     #[cfg(feature = "export")]
     pub fn secret_key(&self) -> Vec<u8> {
@@ -142,3 +147,52 @@ impl Certificate {
         Ok(sealedbox::seal(message, pk!{self}))
     }
 }
+
+/// The public key of a `Certificate`
+///
+/// Can only seal messages. A serialized `Certificate` can also be read as
+/// `PublicCertificate`.
+#[derive(Serialize, Deserialize)]
+pub struct PublicCertificate {
+    public_key: box_::PublicKey
+}
+
+impl PublicCertificate {
+    /// Initialize from the bytes of a public key. `None` if `public_key` is
+    /// not a valid key.
+    pub fn from_public_key(public_key: &[u8]) -> Option<PublicCertificate> {
+        box_::PublicKey::from_slice(public_key)
+            .map(|public_key| PublicCertificate{public_key})
+    }
+
+    /// Initialize from a serialized certificate in [cbor](https://cbor.io/) format
+    pub fn new_from_cbor<T: AsRef<Path>>(path: T) -> Result<PublicCertificate, CertificateError> {
+        debug!{"Reading public certificate from {}", path.as_ref().display()}
+        let f = File::open(path)?;
+
+        Ok(serde_cbor::from_reader(BufReader::new(f))?)
+    }
+
+    /// Serialize a public certificate in [cbor](https://cbor.io/) format
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CertificateError> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    /// Clone the bytes of the public key
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_ref().to_owned()
+    }
+
+    /// Seal a message in a [sealed box](https://download.libsodium.org/doc/public-key_cryptography/sealed_boxes)
+    pub fn seal(&self, message: &[u8]) -> Result<Vec<u8>, CertificateError> {
+        Ok(sealedbox::seal(message, &self.public_key))
+    }
+}
+
+/// A short hex encoded fingerprint of a public key
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let mut state = generichash::State::new(generichash::DIGEST_MIN, None).unwrap();
+    state.update(public_key).unwrap();
+
+    hex::encode(state.finalize().unwrap().as_ref())
+}
@@ -15,7 +15,11 @@
 //! necessarily reflected in the deserialized `Coffer`, as shards can be
 //! uniquely identified by their id.
 //!
-//! Shards (tables with an id) cannot be nested.
+//! Shards (tables with an id) cannot be nested, also not across the files of a
+//! coffer, and an `id` outside of any table is invalid.
+//!
+//! The path of the table a shard is defined in (e.g. `app.frontend`) is kept as
+//! the name of the shard.
 //!
 //! A simple shard with no data
 //! ```toml
@@ -49,6 +53,22 @@
 //! - [Float](https://github.com/toml-lang/toml#user-content-float)
 //! - [Boolean](https://github.com/toml-lang/toml#user-content-boolean)
 //!
+//! ## Metadata
+//! A value can carry metadata by defining it as a table with the value in its
+//! `value` field. Supported metadata fields are
+//! - `description`: a description of the value
+//! - `owner`: who is responsible for the value
+//! - `not_after`: the last date the value is valid, as toml date
+//!
+//! ```toml
+//!   [database]
+//!   id = "0"
+//!   password = { value = "toor", owner = "ops", not_after = 2021-06-30 }
+//! ```
+//!
+//! Values can also be [generated](crate::generate). Generated values have to be
+//! materialized before a coffer file can be read.
+//!
 //! ## Example
 //! ```toml
 //!   [app]
@@ -67,20 +87,60 @@
 //!   passwort = "toor"
 //! ```
 //!
+//! ## References
+//! String values can reference other values by `${shard.key}`, where `shard` is
+//! the name of a shard. References without a shard (`${key}`) refer to values
+//! in the same shard. A literal `${` is written as `$${`.
+//!
+//! Keys and shard names can both contain dots, a reference refers to the
+//! value that exists. References that could refer to several existing values
+//! are an error.
+//!
+//! A value consisting of a single reference takes the type of the referenced
+//! value, otherwise referenced values are formatted into the string.
+//!
+//! A shard can only reference other shards listed in its `reads` field.
+//! References are resolved by `Coffer::resolve_references`.
+//! ```toml
+//!   [database]
+//!   id = "0"
+//!   password = "toor"
+//!
+//!   [app]
+//!   id = "1"
+//!   reads = ["database"]
+//!   url = "postgres://app:${database.password}@db/app"
+//! ```
+//!
+//! ## Reserved keys
+//! The keys `id` and `reads` of a shard are not values: `id` is the shard id
+//! and `reads` lists the shards it can reference. `reads` must be a list of
+//! shard names, other values are an error. Neither can be used as the name of
+//! a value.
+//!
+//! ## Serialization
+//! A `Coffer` can be written back to toml. The resulting document is canonical:
+//! shards are ordered by their name, keys within a shard are ordered with the
+//! shard `id` and `reads` first. Reading the document back yields the same `Coffer`.
+//!
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
 use std::{
-  fmt::Debug,
-  fs::File,
-  io::{BufReader, Read},
-  path::Path,
+  collections::HashMap,
+  convert::TryFrom,
+  fmt::{self, Debug},
+  fs,
+  io,
+  path::{Path, PathBuf},
 };
 
 use quick_error::quick_error;
 use toml::Value as TomlValue;
 use serde::{Serialize, Deserialize};
 
+use crate::date::Date;
+
 quick_error! {
     #[derive(Debug)]
     pub enum CofferError {
@@ -88,6 +148,26 @@ quick_error! {
             from(err)
             display("{}", err)
         }
+        Toml(err: toml::de::Error) {
+            from()
+            display("Invalid toml: {}", err)
+        }
+        Io(err: io::Error) {
+            from()
+            display("Could not read coffer: {}", err)
+        }
+        Duplicate(location: String, reason: String) {
+            display("Duplicate definition of {}: {}", location, reason)
+        }
+        Invalid(location: String, reason: String) {
+            display("Invalid definition of {}: {}", location, reason)
+        }
+        Errors(errors: Vec<CofferError>) {
+            display("{}", errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("\n"))
+        }
+        Reference(location: String, reason: String) {
+            display("Invalid reference in {}: {}", location, reason)
+        }
         Other(err: Box<dyn std::error::Error>) {
             cause(&**err)
         }
@@ -96,8 +176,11 @@ quick_error! {
 
 pub type CofferResult<T> = Result<T, CofferError>;
 
+/// Keys of a shard table that are not values
+pub const RESERVED_KEYS: [&str; 2] = ["id", "reads"];
+
 /// Values supported by `Coffer`
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum CofferValue {
     /// A UTF-8 encoded string
     String(String),
@@ -109,6 +192,30 @@ pub enum CofferValue {
     Boolean(bool)
 }
 
+impl fmt::Display for CofferValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CofferValue::String(s) => write!{f, "{}", s},
+            CofferValue::Integer(i) => write!{f, "{}", i},
+            CofferValue::Float(fl) => write!{f, "{}", fl},
+            CofferValue::Boolean(b) => write!{f, "{}", b}
+        }
+    }
+}
+
+impl From<CofferValue> for TomlValue {
+    fn from(value: CofferValue) -> TomlValue {
+        match value {
+            CofferValue::String(s) => TomlValue::String(s),
+            CofferValue::Integer(i) => TomlValue::Integer(i.into()),
+            // go through the shortest representation of the f32, otherwise
+            // widening adds noise digits (1.4 becomes 1.399999976158142)
+            CofferValue::Float(f) => TomlValue::Float(f.to_string().parse().unwrap()),
+            CofferValue::Boolean(b) => TomlValue::Boolean(b)
+        }
+    }
+}
+
 /// A `CofferKey` defining the shard and the key into the kv-store
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct CofferKey {
@@ -116,6 +223,22 @@ pub struct CofferKey {
     pub key: String
 }
 
+/// Metadata of a value in a `Coffer`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CofferMeta {
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    /// Last date the value is valid
+    pub not_after: Option<Date>
+}
+
+impl CofferMeta {
+    /// Whether the value is expired on `date`
+    pub fn is_expired(&self, date: Date) -> bool {
+        matches!{self.not_after, Some(not_after) if not_after < date}
+    }
+}
+
 /// A key-value store for client data
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CofferShard(pub Vec<(String, CofferValue)>);
@@ -128,6 +251,10 @@ pub trait Coffer {
     /// Push `value` to `key`. Replaces existing values.
     fn push(&mut self, key: CofferKey, value: CofferValue);
 
+    /// Remove the value and metadata at `key`. Returns the removed value, `None`
+    /// if there was no value for `key`.
+    fn remove(&mut self, key: &CofferKey) -> Option<CofferValue>;
+
     /// Retrieve `value` at path. `None` if there is no `value` for `key`.
     fn get(&self, key: &CofferKey) -> Option<CofferValue>;
 
@@ -135,76 +262,378 @@ pub trait Coffer {
     fn get_shard<T>(&self, shard: T) -> Option<CofferShard>
     where T: AsRef<str>;
 
+    /// Retrieve the ids of all shards in the coffer
+    fn get_shard_ids(&self) -> Vec<String>;
+
+    /// Set the `name` of `shard`. Creates an empty shard if there is no
+    /// `CofferShard` for `shard` yet.
+    fn put_shard_name(&mut self, shard: String, name: String);
+
+    /// Retrieve the name of a shard. `None` if `shard` has no name.
+    fn get_shard_name<T>(&self, shard: T) -> Option<String>
+    where T: AsRef<str>;
+
+    /// Set the names of the shards `shard` can reference
+    fn put_shard_reads(&mut self, shard: String, reads: Vec<String>);
+
+    /// Retrieve the names of the shards `shard` can reference
+    fn get_shard_reads<T>(&self, shard: T) -> Vec<String>
+    where T: AsRef<str>;
+
+    /// Set the metadata of the value at `key`
+    fn put_meta(&mut self, key: CofferKey, meta: CofferMeta);
+
+    /// Retrieve the metadata of the value at `key`. `None` if there is none.
+    fn get_meta(&self, key: &CofferKey) -> Option<CofferMeta>;
+
     /// Deserializes a `Coffer` from a toml file
-    fn from_toml_path(toml_path: &Path) -> Self
+    fn from_toml_path(toml_path: &Path) -> CofferResult<Self>
     where Self: Coffer + Default
     {
-        // read the secrets file into a temporary string
-        let mut file = BufReader::new(File::open(toml_path).unwrap());
-        let mut secrets_buf = String::new();
-        file.read_to_string(&mut secrets_buf).unwrap();
-
-        Coffer::from_toml(&secrets_buf)
+        Coffer::from_toml(&fs::read_to_string(toml_path)?)
     }
 
-    fn from_toml(toml: &str) -> Self
+    /// Deserializes a `Coffer` from a toml document
+    fn from_toml(toml: &str) -> CofferResult<Self>
     where Self: Coffer + Default
     {
         // call implementation to create an empty coffer
         let mut coffer = Self::default();
 
+        coffer.put_toml(toml)?;
+
+        Ok(coffer)
+    }
+
+    /// Put the shards of a toml document into the `Coffer`. Errors if a shard
+    /// is already in the `Coffer`.
+    fn put_toml(&mut self, toml: &str) -> CofferResult<()> {
         // parse the string into a toml Table
-        let clients: toml::value::Table = match toml.parse::<TomlValue>().unwrap() {
+        let clients: toml::value::Table = match toml.parse::<TomlValue>()? {
             TomlValue::Table(t) => t,
-            _ => panic!{"Invalid secrets file"}
+            _ => return Err(CofferError::Msg("Invalid secrets file"))
         };
 
-        coffer.from_toml_table(&clients);
+        self.from_toml_table("", &clients)
+    }
+
+    /// Reads the shards in `toml_table` into the `Coffer`. `path` is the
+    /// dotted path of `toml_table` in the toml document.
+    ///
+    /// Invalid definitions are skipped and reported together in
+    /// `CofferError::Errors`.
+    fn from_toml_table(&mut self, path: &str, toml_table: &toml::value::Table) -> CofferResult<()> {
+        let mut errors = Vec::new();
+        read_toml_table(self, path, toml_table, &mut errors);
 
-        coffer
+        if errors.is_empty() { Ok(()) } else { Err(CofferError::Errors(errors)) }
     }
 
-    fn from_toml_table(&mut self, toml_table: &toml::value::Table) {
-        // table has an no id, recourse into subtables
-        if toml_table.get("id").is_none() {
-            for (_key, val) in toml_table.iter() {
-                match val {
-                    TomlValue::Table(subtable) => {
-                        self.from_toml_table(subtable);
-                    },
-                    _ => panic!{"Invalid secrets file"}
-                }
+    /// Resolves all references in string values of the `Coffer`
+    ///
+    /// Errors if a reference can't be resolved, is not readable by the shard
+    /// it appears in, or is cyclic. All invalid references are reported
+    /// together in `CofferError::Errors`.
+    fn resolve_references(&mut self) -> CofferResult<()>
+    where Self: Sized
+    {
+        crate::reference::resolve(self)
+    }
+
+    /// The values each value of the `Coffer` references directly
+    ///
+    /// Errors for invalid references like `Coffer::resolve_references`, except
+    /// for cycles.
+    fn references(&self) -> CofferResult<HashMap<CofferKey, Vec<CofferKey>>>
+    where Self: Sized
+    {
+        crate::reference::references(self)
+    }
+
+    /// Serializes a `Coffer` into a canonical toml document
+    ///
+    /// Shards are written as tables named by their shard name and ordered by
+    /// name. Shards without a name, or with an empty one, are named by their
+    /// id. Keys are ordered within a shard, with the `id` and `reads` first.
+    fn to_toml(&self) -> String {
+        let mut shards: Vec<(String, String)> = self.get_shard_ids()
+            .into_iter()
+            .map(|id| {
+                let name = self.get_shard_name(&id).filter(|name| !name.is_empty());
+                (name.unwrap_or_else(|| toml_key(&id)), id)
+            })
+            .collect();
+        shards.sort();
+
+        let mut toml = String::new();
+        for (name, id) in shards {
+            if !toml.is_empty() { toml.push('\n') }
+
+            toml.push_str(&format!{"[{}]\n", name});
+            toml.push_str(&format!{"id = {}\n", toml_string(&id)});
+
+            let reads = self.get_shard_reads(&id);
+            if !reads.is_empty() {
+                let reads: Vec<String> = reads.iter().map(|r| toml_string(r)).collect();
+                toml.push_str(&format!{"reads = [{}]\n", reads.join(", ")});
+            }
+
+            let mut values = self.get_shard(&id)
+                .map(|shard| shard.0)
+                .unwrap_or_default();
+            values.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+
+            for (key, value) in values {
+                let value = match value {
+                    CofferValue::String(s) => toml_string(&s),
+                    value => TomlValue::from(value).to_string()
+                };
+
+                let meta = self.get_meta(&CofferKey{shard: id.clone(), key: key.clone()})
+                    .unwrap_or_default();
+                let value = if meta == CofferMeta::default() { value } else { meta_table(value, &meta) };
+
+                toml.push_str(&format!{"{} = {}\n", toml_key(&key), value});
+            }
+        }
+
+        toml
+    }
+}
+
+/// Expand directories in `paths` to the coffer files they contain
+///
+/// Files in a directory are sorted by name. Hidden files and subdirectories are
+/// skipped. Errors with the path that could not be read.
+pub fn coffer_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, (PathBuf, io::Error)> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+
+        let io_err = |err| (path.clone(), err);
+        let mut dir_files = Vec::new();
+        for entry in fs::read_dir(path).map_err(io_err)? {
+            let entry = entry.map_err(io_err)?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if !hidden && entry.file_type().map_err(io_err)?.is_file() {
+                dir_files.push(entry.path());
+            }
+        }
+        dir_files.sort();
+
+        files.extend(dir_files);
+    }
+
+    Ok(files)
+}
+
+fn read_toml_table<C>(coffer: &mut C, path: &str, toml_table: &toml::value::Table, errors: &mut Vec<CofferError>)
+where C: Coffer + ?Sized
+{
+    let invalid = |location: String, reason: &str| CofferError::Invalid(location, reason.to_string());
+
+    // table has an no id, recourse into subtables
+    if toml_table.get("id").is_none() {
+        for (key, val) in toml_table.iter() {
+            match val {
+                TomlValue::Table(subtable) => {
+                    read_toml_table(coffer, &table_path(path, key), subtable, errors);
+                },
+                _ => errors.push(invalid(table_path(path, key), "value outside of a shard"))
+            }
+        }
+
+        return;
+    }
+
+    /*
+     * Parse a single shard/table, this is known to have an id
+     *
+     * [files]
+     * id = "ABC-DEF-GHE"
+     * secret_string = "secret value1"
+     * secret_int = 12345
+     * secret_bool = true
+     */
+    // the name of a shard is the path of its table
+    if path.is_empty() {
+        return errors.push(invalid("id".to_string(), "shards must be defined in a table"));
+    }
+
+    let shard = match toml_table.get("id").and_then(|id| id.as_str()) {
+        Some(shard) => shard,
+        None => return errors.push(invalid(path.to_string(), "id must be a string"))
+    };
+
+    if let Some(name) = coffer.get_shard_name(shard) {
+        let reason = format!{"id already used by {}", name};
+        return errors.push(CofferError::Duplicate(path.to_string(), reason));
+    }
+    if coffer.get_shard_ids().iter().any(|id| coffer.get_shard_name(id).as_deref() == Some(path)) {
+        let reason = "name already used by another shard".to_string();
+        return errors.push(CofferError::Duplicate(path.to_string(), reason));
+    }
+    // shards of other documents can't be written into one document
+    let nested = coffer.get_shard_ids().iter()
+        .filter_map(|id| coffer.get_shard_name(id))
+        .find(|name| is_nested(name, path) || is_nested(path, name));
+    if let Some(name) = nested {
+        return errors.push(invalid(path.to_string(), &format!{"shards cannot be nested, overlaps {}", name}));
+    }
+
+    coffer.put_shard_name(shard.to_string(), path.to_string());
+
+    if let Some(reads) = toml_table.get("reads") {
+        let reads: Option<Vec<String>> = reads.as_array()
+            .and_then(|reads| reads.iter().map(|r| r.as_str().map(str::to_string)).collect());
+
+        match reads {
+            Some(reads) => coffer.put_shard_reads(shard.to_string(), reads),
+            None => errors.push(invalid(table_path(path, "reads"),
+                                        "reads is reserved for the shards this shard references, must be a list of shard names"))
+        }
+    }
+
+    for (key, val) in toml_table {
+        if "id" == key { continue } // ids are for sharding
+        if "reads" == key { continue } // reads are for referencing
+
+        let location = table_path(path, key);
+        let (value, meta) = match val {
+            TomlValue::Table(t) if t.contains_key("id") => {
+                errors.push(invalid(location, "shards cannot be nested"));
+                continue
+            },
+            TomlValue::Table(t) if t.contains_key("generate") => {
+                errors.push(invalid(location, "generated value is not materialized"));
+                continue
+            },
+            TomlValue::Table(t) => match read_meta_table(t) {
+                Ok((value, meta)) => (value, Some(meta)),
+                Err(reason) => { errors.push(invalid(location, &reason)); continue }
+            },
+            val => match read_value(val) {
+                Ok(value) => (value, None),
+                Err(reason) => { errors.push(invalid(location, &reason)); continue }
             }
+        };
 
-            return;
+        let key = CofferKey{shard: shard.to_string(), key: key.to_owned()};
+        if coffer.put(key.clone(), value).is_err() {
+            errors.push(CofferError::Duplicate(location, "key already defined".into()));
+        } else if let Some(meta) = meta {
+            coffer.put_meta(key, meta);
         }
+    }
+}
+
+/// Read a toml value into a `CofferValue`
+fn read_value(val: &TomlValue) -> Result<CofferValue, String> {
+    match val {
+        TomlValue::String(s) => Ok(CofferValue::String(s.to_owned())),
+        TomlValue::Integer(i) => i32::try_from(*i)
+            .map(CofferValue::Integer)
+            .map_err(|_| "integer exceeds 32 bit".to_string()),
+        TomlValue::Float(f) if f.is_finite() && (*f as f32).is_infinite() =>
+            Err("float exceeds 32 bit".to_string()),
+        TomlValue::Float(f) => Ok(CofferValue::Float(*f as f32)),
+        TomlValue::Boolean(b) => Ok(CofferValue::Boolean(*b)),
+        _ => Err(format!{"{} values are unsupported", val.type_str()})
+    }
+}
+
+/// Read a value with metadata
+///
+/// ```toml
+/// { value = "toor", description = "", owner = "", not_after = 2020-01-01 }
+/// ```
+fn read_meta_table(table: &toml::value::Table) -> Result<(CofferValue, CofferMeta), String> {
+    let value = table.get("value")
+        .ok_or_else(|| "table values are unsupported".to_string())
+        .and_then(read_value)?;
 
-        /*
-         * Parse a single shard/table, this is known to have an id
-         *
-         * [files]
-         * id = "ABC-DEF-GHE"
-         * secret_string = "secret value1"
-         * secret_int = 12345
-         * secret_bool = true
-         */
-        let shard = toml_table.get("id").and_then(|id| id.as_str()).unwrap();
+    let string = |field: &str| match table.get(field) {
+        None => Ok(None),
+        Some(TomlValue::String(s)) => Ok(Some(s.to_owned())),
+        Some(_) => Err(format!{"{} must be a string", field})
+    };
 
-        for (key, val) in toml_table {
-            if "id" == key { continue } // ids are for sharding
+    let not_after = match table.get("not_after") {
+        None => None,
+        Some(TomlValue::Datetime(d)) => Some(d.to_string().parse::<Date>()
+            .map_err(|_| "not_after must be a date without time".to_string())?),
+        Some(_) => return Err("not_after must be a date".to_string())
+    };
 
-            let value = match val {
-                TomlValue::String(s) => CofferValue::String(s.to_owned()),
-                TomlValue::Integer(i) => CofferValue::Integer(*i as i32),
-                TomlValue::Float(f) => CofferValue::Float(*f as f32),
-                TomlValue::Boolean(b) => CofferValue::Boolean(*b),
-                _ => panic!{"Value {:?} unsupported", val}
-            };
+    let meta = CofferMeta {
+        description: string("description")?,
+        owner: string("owner")?,
+        not_after
+    };
 
-            let key =  key.to_owned();
-            let shard = shard.to_string();
-            self.put(CofferKey{shard, key}, value).unwrap();
+    match table.keys().find(|k| !["value", "description", "owner", "not_after"].contains(&k.as_str())) {
+        Some(field) => Err(format!{"unknown metadata field {}", field}),
+        None => Ok((value, meta))
+    }
+}
+
+/// Write a toml `value` with its `meta`data as inline table
+fn meta_table(value: String, meta: &CofferMeta) -> String {
+    let mut fields = vec![format!{"value = {}", value}];
+
+    if let Some(description) = &meta.description {
+        fields.push(format!{"description = {}", toml_string(description)});
+    }
+    if let Some(owner) = &meta.owner {
+        fields.push(format!{"owner = {}", toml_string(owner)});
+    }
+    if let Some(not_after) = &meta.not_after {
+        fields.push(format!{"not_after = {}", not_after});
+    }
+
+    format!{"{{ {} }}", fields.join(", ")}
+}
+
+/// Quote `key` for use in a toml document if it is not a valid bare key
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if bare { key.to_string() } else { toml_string(key) }
+}
+
+/// Write `s` as a toml basic string
+// toml's own serializer chooses between string representations and does not
+// escape all control characters
+fn toml_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!{"\\u{:04X}", c as u32}),
+            c => escaped.push(c)
         }
     }
 
+    escaped.push('"');
+    escaped
+}
+
+/// Whether the table at `inner` is inside the table at `outer`
+fn is_nested(outer: &str, inner: &str) -> bool {
+    inner.starts_with(outer) && inner[outer.len()..].starts_with('.')
+}
+
+/// Append `key` to the dotted toml table path `path`
+pub(crate) fn table_path(path: &str, key: &str) -> String {
+    if path.is_empty() { toml_key(key) } else { format!{"{}.{}", path, toml_key(key)} }
 }
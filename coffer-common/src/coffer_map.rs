@@ -0,0 +1,614 @@
+//! Thread-safe coffer implementation backed by hash map
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+use std::collections::hash_map::{HashMap, Entry};
+
+use crate::coffer::*;
+
+type ShardedCoffer = HashMap<String, HashMap<String, CofferValue>>;
+pub struct CofferMap {
+    shards: RwLock<ShardedCoffer>,
+    names: RwLock<HashMap<String, String>>,
+    reads: RwLock<HashMap<String, Vec<String>>>,
+    meta: RwLock<HashMap<CofferKey, CofferMeta>>
+}
+
+impl CofferMap {
+    pub fn new() -> CofferMap {
+        CofferMap {
+            shards: RwLock::new(HashMap::new()),
+            names: RwLock::new(HashMap::new()),
+            reads: RwLock::new(HashMap::new()),
+            meta: RwLock::new(HashMap::new())
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, ShardedCoffer> {
+        self.shards.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, ShardedCoffer> {
+        self.shards.write().unwrap()
+    }
+}
+
+impl Coffer for CofferMap {
+  fn put(&mut self, key: CofferKey, value: CofferValue) -> CofferResult<()> {
+        let mut lock = self.write();
+
+        match lock.get_mut(&key.shard) {
+            Some(shard) => {
+                match shard.entry(key.key) {
+                    Entry::Occupied(_) => Err(CofferError::Msg("Key exists")),
+                    Entry::Vacant(v) => { v.insert(value); Ok(()) }
+                }
+            }
+            None => {
+                lock.insert(key.shard.clone(), HashMap::new());
+                lock.get_mut(&key.shard).unwrap().insert(key.key, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn push(&mut self, key: CofferKey, value: CofferValue) {
+        let mut lock = self.write();
+
+        match lock.get_mut(&key.shard) {
+            Some(shard) => {
+                shard.insert(key.key, value);
+            }
+            None => {
+                lock.insert(key.shard.clone(), HashMap::new());
+                lock.get_mut(&key.shard).unwrap().insert(key.key, value);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &CofferKey) -> Option<CofferValue> {
+        self.meta.write().unwrap().remove(key);
+
+        self.write()
+            .get_mut(&key.shard)
+            .and_then(|shard| shard.remove(&key.key))
+    }
+
+    fn get(&self, key: &CofferKey) -> Option<CofferValue> {
+        let lock = self.read();
+
+        lock.get(&key.shard)
+            .and_then( |shard| { shard.get(&key.key) } )
+            .map(|o| o.clone())
+    }
+
+    fn get_shard<T>(&self, shard: T) -> Option<CofferShard>
+    where T: AsRef<str>
+    {
+        let lock = self.read();
+
+        debug!{"Coffer {:?}", *lock}
+
+        let map_to_vec = |map: &HashMap<String, CofferValue>| {
+            map.iter()
+               .map(|(k,v)| (k.clone(), v.clone()))
+               .collect::<Vec<(String, CofferValue)>>()
+        };
+
+        lock.get(shard.as_ref())
+            .and_then(|s| Some(CofferShard(map_to_vec(s))))
+    }
+
+    fn get_shard_ids(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    fn put_shard_name(&mut self, shard: String, name: String) {
+        self.write().entry(shard.clone()).or_default();
+        self.names.write().unwrap().insert(shard, name);
+    }
+
+    fn get_shard_name<T>(&self, shard: T) -> Option<String>
+    where T: AsRef<str>
+    {
+        self.names.read().unwrap()
+            .get(shard.as_ref())
+            .cloned()
+    }
+
+    fn put_shard_reads(&mut self, shard: String, reads: Vec<String>) {
+        self.reads.write().unwrap().insert(shard, reads);
+    }
+
+    fn get_shard_reads<T>(&self, shard: T) -> Vec<String>
+    where T: AsRef<str>
+    {
+        self.reads.read().unwrap()
+            .get(shard.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn put_meta(&mut self, key: CofferKey, meta: CofferMeta) {
+        self.meta.write().unwrap().insert(key, meta);
+    }
+
+    fn get_meta(&self, key: &CofferKey) -> Option<CofferMeta> {
+        self.meta.read().unwrap()
+            .get(key)
+            .cloned()
+    }
+}
+
+impl Default for CofferMap {
+    fn default() -> Self {
+        CofferMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use std::path::Path;
+    use toml::{Value as TomlValue, value::Table};
+
+    use crate::date::Date;
+
+    type Contents = Vec<(String, Option<String>, Vec<(String, CofferValue, Option<CofferMeta>)>)>;
+
+    fn contents(coffer: &CofferMap) -> Contents {
+        let mut ids = coffer.get_shard_ids();
+        ids.sort();
+
+        ids.into_iter()
+           .map(|id| {
+               let name = coffer.get_shard_name(&id);
+               let mut values: Vec<_> = coffer.get_shard(&id).unwrap().0.into_iter()
+                   .map(|(key, value)| {
+                       let meta = coffer.get_meta(&CofferKey{shard: id.clone(), key: key.clone()})
+                           .filter(|meta| *meta != CofferMeta::default());
+                       (key, value, meta)
+                   })
+                   .collect();
+               values.sort_by(|(k1, _, _), (k2, _, _)| k1.cmp(k2));
+               (id, name, values)
+           })
+           .collect()
+    }
+
+    #[test]
+    fn to_toml_is_canonical() {
+        let coffer = CofferMap::from_toml(r#"
+            [database]
+            user = "root"
+            id = "0"
+
+            [app]
+            [app.frontend]
+            password = "admin"
+            id = "1"
+            font_size = 1.4
+
+            [app."back end"]
+            id = "2"
+            cors = true
+            retries = 3
+        "#).unwrap();
+
+        assert_eq!{coffer.to_toml(), r#"[app."back end"]
+id = "2"
+cors = true
+retries = 3
+
+[app.frontend]
+id = "1"
+font_size = 1.4
+password = "admin"
+
+[database]
+id = "0"
+user = "root"
+"#};
+    }
+
+    #[test]
+    fn to_toml_keeps_empty_shards() {
+        let coffer = CofferMap::from_toml("[app]\nid = \"1\"\n").unwrap();
+
+        assert_eq!{coffer.to_toml(), "[app]\nid = \"1\"\n"};
+    }
+
+    #[test]
+    fn to_toml_names_unnamed_shards_by_id() {
+        let mut coffer = CofferMap::new();
+        coffer.put_shard_name("1".into(), "".into());
+        coffer.put(CofferKey{shard: "1".into(), key: "a".into()}, CofferValue::Integer(1)).unwrap();
+
+        assert_eq!{coffer.to_toml(), "[1]\nid = \"1\"\na = 1\n"};
+        assert_eq!{CofferMap::from_toml(&coffer.to_toml()).unwrap().to_toml(), coffer.to_toml()};
+    }
+
+    #[test]
+    fn put_toml_rejects_shard_outside_of_table() {
+        let err = CofferMap::new().put_toml("id = \"1\"\na = 1").unwrap_err();
+
+        assert_eq!{err.to_string(), "Invalid definition of id: shards must be defined in a table"};
+    }
+
+    #[test]
+    fn put_toml_rejects_shards_nested_across_documents() {
+        let mut coffer = CofferMap::from_toml("[app]\nid = \"1\"").unwrap();
+
+        let err = coffer.put_toml("[app.frontend]\nid = \"2\"").unwrap_err();
+        assert_eq!{err.to_string(), "Invalid definition of app.frontend: shards cannot be nested, overlaps app"};
+
+        let err = coffer.put_toml("[ap]\nid = \"3\"\n[app.b]\n[app.b.c]\nid = \"4\"").unwrap_err();
+        assert_eq!{err.to_string(), "Invalid definition of app.b.c: shards cannot be nested, overlaps app"};
+        assert_eq!{coffer.get_shard_name("3").as_deref(), Some("ap")};
+    }
+
+    #[test]
+    fn from_toml_reports_invalid_documents() {
+        assert!{matches!{CofferMap::from_toml("[app"), Err(CofferError::Toml(_))}};
+        assert!{matches!{CofferMap::from_toml("a = 1"), Err(CofferError::Errors(_))}};
+        assert!{matches!{CofferMap::from_toml_path(Path::new("/nonexistent")), Err(CofferError::Io(_))}};
+    }
+
+    #[test]
+    fn to_toml_escapes_strings() {
+        let mut coffer = CofferMap::new();
+        let key = CofferKey{shard: "0".into(), key: "a \"key\"".into()};
+        let value = CofferValue::String("\\ \"\u{7f}\u{1b}\n\t".into());
+        coffer.put(key.clone(), value.clone()).unwrap();
+
+        let roundtrip = CofferMap::from_toml(&coffer.to_toml()).unwrap();
+
+        assert_eq!{roundtrip.get(&key), Some(value)};
+    }
+
+    #[test]
+    fn put_toml_merges_documents() {
+        let mut coffer = CofferMap::from_toml("[app]\nid = \"1\"\na = 1").unwrap();
+        coffer.put_toml("[db]\nid = \"2\"\nb = 2").unwrap();
+
+        assert_eq!{coffer.get_shard_name("1").as_deref(), Some("app")};
+        assert_eq!{coffer.get_shard_name("2").as_deref(), Some("db")};
+    }
+
+    #[test]
+    fn put_toml_rejects_duplicate_shards() {
+        let mut coffer = CofferMap::from_toml("[app]\nid = \"1\"\na = 1").unwrap();
+
+        assert_eq!{
+            coffer.put_toml("[other]\nid = \"1\"\nb = 2").unwrap_err().to_string(),
+            "Duplicate definition of other: id already used by app"
+        };
+        assert_eq!{
+            coffer.put_toml("[app]\nid = \"2\"").unwrap_err().to_string(),
+            "Duplicate definition of app: name already used by another shard"
+        };
+    }
+
+    #[test]
+    fn put_toml_reports_all_problems() {
+        let mut coffer = CofferMap::new();
+        let err = coffer.put_toml(r#"
+            outside = 1
+            [app]
+            id = "1"
+            big = 5000000000
+            list = [1, 2]
+            reads = "secret"
+            [app.nested]
+            id = "2"
+        "#).unwrap_err();
+
+        assert_eq!{err.to_string(), "\
+            Invalid definition of app.reads: reads is reserved for the shards this shard references, must be a list of shard names\n\
+            Invalid definition of app.big: integer exceeds 32 bit\n\
+            Invalid definition of app.list: array values are unsupported\n\
+            Invalid definition of app.nested: shards cannot be nested\n\
+            Invalid definition of outside: value outside of a shard"};
+    }
+
+    #[test]
+    fn put_toml_reads_metadata() {
+        let coffer = CofferMap::from_toml(r#"
+            [app]
+            id = "1"
+            password = { value = "toor", owner = "ops", not_after = 2021-06-30 }
+        "#).unwrap();
+
+        let key = CofferKey{shard: "1".into(), key: "password".into()};
+        let meta = coffer.get_meta(&key).unwrap();
+
+        assert_eq!{coffer.get(&key), Some(CofferValue::String("toor".into()))};
+        assert_eq!{meta.owner.as_deref(), Some("ops")};
+        assert!{!meta.is_expired("2021-06-30".parse().unwrap())};
+        assert!{meta.is_expired("2021-07-01".parse().unwrap())};
+
+        assert_eq!{coffer.to_toml(), "[app]\nid = \"1\"\n\
+            password = { value = \"toor\", owner = \"ops\", not_after = 2021-06-30 }\n"};
+    }
+
+    #[test]
+    fn put_toml_rejects_invalid_metadata() {
+        let mut coffer = CofferMap::new();
+        let err = coffer.put_toml(r#"
+            [app]
+            id = "1"
+            a = { owner = "ops" }
+            b = { value = 1, not_after = "2021-06-30" }
+            c = { value = 1, expires = 2021-06-30 }
+        "#).unwrap_err();
+
+        assert_eq!{err.to_string(), "\
+            Invalid definition of app.a: table values are unsupported\n\
+            Invalid definition of app.b: not_after must be a date\n\
+            Invalid definition of app.c: unknown metadata field expires"};
+    }
+
+    const REFERENCES: &str = r#"
+        [database]
+        id = "0"
+        user = "app"
+        password = "toor"
+        port = 5432
+
+        [app]
+        id = "1"
+        reads = ["database"]
+        url = "postgres://${database.user}:${password}@db:${database.port}/app"
+        password = "${database.password}"
+        port = "${database.port}"
+        template = "$${database.password}"
+    "#;
+
+    #[test]
+    fn references_are_direct() {
+        let coffer = CofferMap::from_toml(REFERENCES).unwrap();
+        let references = coffer.references().unwrap();
+
+        let key = |shard: &str, key: &str| CofferKey{shard: shard.into(), key: key.into()};
+        assert_eq!{references[&key("1", "url")],
+                   vec![key("0", "user"), key("1", "password"), key("0", "port")]};
+        assert_eq!{references[&key("1", "template")], vec![]};
+        assert_eq!{references[&key("0", "port")], vec![]};
+    }
+
+    #[test]
+    fn resolve_references() {
+        let mut coffer = CofferMap::from_toml(REFERENCES).unwrap();
+        coffer.resolve_references().unwrap();
+
+        let get = |key: &str| coffer.get(&CofferKey{shard: "1".into(), key: key.into()}).unwrap();
+        assert_eq!{get("url"), CofferValue::String("postgres://app:toor@db:5432/app".into())};
+        assert_eq!{get("port"), CofferValue::Integer(5432)};
+        assert_eq!{get("template"), CofferValue::String("${database.password}".into())};
+    }
+
+    #[test]
+    fn resolve_references_keeps_earliest_expiry() {
+        let mut coffer = CofferMap::from_toml(r#"
+            [database]
+            id = "0"
+            password = { value = "toor", not_after = 2021-06-30 }
+
+            [app]
+            id = "1"
+            reads = ["database"]
+            url = { value = "${database.password}@db", owner = "ops", not_after = 2022-01-01 }
+            indirect = "${url}"
+            early = { value = "${url}", not_after = 2021-01-01 }
+            plain = "x"
+        "#).unwrap();
+        coffer.resolve_references().unwrap();
+
+        let meta = |key: &str| coffer.get_meta(&CofferKey{shard: "1".into(), key: key.into()}).unwrap_or_default();
+        let expiry = Date::new(2021, 6, 30);
+        assert_eq!{meta("url").not_after, expiry};
+        assert_eq!{meta("url").owner.as_deref(), Some("ops")};
+        assert_eq!{meta("indirect").not_after, expiry};
+        assert_eq!{meta("early").not_after, Date::new(2021, 1, 1)};
+        assert_eq!{meta("plain").not_after, None};
+    }
+
+    #[test]
+    fn to_toml_keeps_references() {
+        let coffer = CofferMap::from_toml(REFERENCES).unwrap();
+
+        let roundtrip = CofferMap::from_toml(&coffer.to_toml()).unwrap();
+
+        assert_eq!{contents(&coffer), contents(&roundtrip)};
+        assert_eq!{roundtrip.get_shard_reads("1"), vec!["database".to_string()]};
+    }
+
+    fn resolve_error(toml: &str) -> String {
+        CofferMap::from_toml(toml).unwrap().resolve_references().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn resolve_rejects_unknown_references() {
+        assert_eq!{
+            resolve_error("[app]\nid = \"1\"\na = \"${b}\""),
+            "Invalid reference in app.a: unknown value ${b}"
+        };
+        assert_eq!{
+            resolve_error("[app]\nid = \"1\"\na = \"${db.b}\""),
+            "Invalid reference in app.a: unknown shard db in ${db.b}"
+        };
+        assert_eq!{
+            resolve_error("[app]\nid = \"1\"\nreads = [\"db\"]"),
+            "Invalid reference in app: reads unknown shard db"
+        };
+    }
+
+    #[test]
+    fn resolve_finds_keys_with_dots() {
+        let mut coffer = CofferMap::from_toml(r#"
+            [app]
+            id = "1"
+            reads = ["db"]
+            "a.b" = "own"
+            c = "${a.b}"
+            d = "${db.e.f}"
+            [db]
+            id = "2"
+            "e.f" = "db"
+        "#).unwrap();
+        coffer.resolve_references().unwrap();
+
+        assert_eq!{coffer.get(&CofferKey{shard: "1".into(), key: "c".into()}), Some(CofferValue::String("own".into()))};
+        assert_eq!{coffer.get(&CofferKey{shard: "1".into(), key: "d".into()}), Some(CofferValue::String("db".into()))};
+    }
+
+    #[test]
+    fn resolve_rejects_ambiguous_references() {
+        assert_eq!{
+            resolve_error("[a]\nid = \"0\"\nb = 1\n[app]\nid = \"1\"\nreads = [\"a\"]\n\"a.b\" = 2\nc = \"${a.b}\""),
+            "Invalid reference in app.c: ambiguous reference ${a.b}, could be a.b or app.a.b"
+        };
+    }
+
+    #[test]
+    fn resolve_rejects_unreadable_references() {
+        assert_eq!{
+            resolve_error("[db]\nid = \"0\"\na = 1\n[app]\nid = \"1\"\na = \"${db.a}\""),
+            "Invalid reference in app.a: shard db is not readable"
+        };
+    }
+
+    #[test]
+    fn resolve_rejects_cyclic_references() {
+        let error = resolve_error("[app]\nid = \"1\"\na = \"${b}\"\nb = \"${c}\"\nc = \"${a}\"");
+
+        assert!{error.contains("cyclic reference"), "{}", error};
+    }
+
+    fn value() -> impl Strategy<Value = TomlValue> {
+        prop_oneof![
+            // toml's serializer writes some control characters unescaped,
+            // which it can't read back. See `to_toml_escapes_strings`.
+            "\\PC*".prop_map(TomlValue::String),
+            any::<i32>().prop_map(|i| TomlValue::Integer(i.into())),
+            (prop::num::f32::NORMAL | prop::num::f32::ZERO | prop::num::f32::INFINITE)
+                .prop_map(|f| TomlValue::Float(f.into())),
+            any::<bool>().prop_map(TomlValue::Boolean)
+        ]
+    }
+
+    /// A value, optionally with metadata
+    fn meta_value() -> impl Strategy<Value = TomlValue> {
+        let date = (0..100_000i64).prop_map(|days| TomlValue::Datetime(
+            crate::date::Date::from_days(days).to_string().parse().unwrap()));
+        let meta = (prop::option::of("\\PC*"), prop::option::of("\\PC*"), prop::option::of(date));
+
+        (value(), prop::option::of(meta)).prop_map(|(value, meta)| match meta {
+            None => value,
+            Some((description, owner, not_after)) => {
+                let mut table = Table::new();
+                table.insert("value".into(), value);
+                if let Some(description) = description {
+                    table.insert("description".into(), TomlValue::String(description));
+                }
+                if let Some(owner) = owner {
+                    table.insert("owner".into(), TomlValue::String(owner));
+                }
+                if let Some(not_after) = not_after {
+                    table.insert("not_after".into(), not_after);
+                }
+                TomlValue::Table(table)
+            }
+        })
+    }
+
+    fn shard() -> impl Strategy<Value = Table> {
+        let key = "\\PC{1,12}".prop_filter("id is reserved", |k| k != "id");
+
+        (any::<[u8; 32]>(), prop::collection::btree_map(key, meta_value(), 0..8))
+            .prop_map(|(id, values)| {
+                let mut shard: Table = values.into_iter().collect();
+                shard.insert("id".into(), TomlValue::String(hex::encode_upper(id)));
+                shard
+            })
+    }
+
+    /// A toml coffer document with optionally grouped shards
+    fn document() -> impl Strategy<Value = String> {
+        let group = prop::option::of(prop::sample::select(vec!["app", "client", "db.servers"]));
+        let shards = prop::collection::vec((group, "[a-z _.]{1,8}", shard()), 0..8);
+
+        shards.prop_map(|shards| {
+            let mut document = Table::new();
+
+            // suffix names with their index, so they are unique and never
+            // clash with a group
+            for (i, (group, name, shard)) in shards.into_iter().enumerate() {
+                let name = format!{"{}{}", name, i};
+                let tables = match group {
+                    Some(group) => document.entry(group.to_string())
+                        .or_insert_with(|| TomlValue::Table(Table::new()))
+                        .as_table_mut()
+                        .unwrap(),
+                    None => &mut document
+                };
+                tables.insert(name, TomlValue::Table(shard));
+            }
+
+            toml::to_string(&TomlValue::Table(document)).unwrap()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn to_toml_roundtrips(document in document()) {
+            let coffer = CofferMap::from_toml(&document).unwrap();
+            let toml = coffer.to_toml();
+            let roundtrip = CofferMap::from_toml(&toml).unwrap();
+
+            prop_assert_eq!(contents(&coffer), contents(&roundtrip));
+        }
+
+        #[test]
+        fn to_toml_roundtrips_without_nested_shards(document in document(), key in "[a-z]{1,8}", above in any::<bool>()) {
+            let mut coffer = CofferMap::from_toml(&document).unwrap();
+            let names: Vec<String> = coffer.get_shard_ids().iter()
+                .filter_map(|id| coffer.get_shard_name(id))
+                .collect();
+
+            // a shard inside or around each shard of the document
+            for (i, name) in names.iter().enumerate() {
+                // the last part of a name is quoted if it is not bare
+                let last = match name.strip_suffix('"') {
+                    Some(quoted) => quoted.rfind('"').unwrap(),
+                    None => name.rfind('.').map_or(0, |dot| dot + 1)
+                };
+                let path = match last {
+                    0 => format!{"{}.{}", name, key},
+                    last if above => name[..last - 1].to_string(),
+                    _ => format!{"{}.{}", name, key}
+                };
+                let nested = format!{"[{}]\nid = \"nested{}\"", path, i};
+
+                prop_assert!(coffer.put_toml(&nested).is_err(), "{} is not rejected", path);
+            }
+
+            let roundtrip = CofferMap::from_toml(&coffer.to_toml()).unwrap();
+            prop_assert_eq!(contents(&coffer), contents(&roundtrip));
+        }
+
+        #[test]
+        fn to_toml_is_stable(document in document()) {
+            let toml = CofferMap::from_toml(&document).unwrap().to_toml();
+
+            prop_assert_eq!(CofferMap::from_toml(&toml).unwrap().to_toml(), toml);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Calendar dates for value expiry
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A calendar date in the proleptic Gregorian calendar
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i64,
+    month: i64,
+    day: i64
+}
+
+impl Date {
+    /// `None` if `year`, `month`, `day` is not a valid date
+    pub fn new(year: i64, month: i64, day: i64) -> Option<Date> {
+        let date = Date {year, month, day};
+
+        if (1..=12).contains(&month) && (1..=31).contains(&day)
+            && Date::from_days(date.days()) == date {
+            Some(date)
+        } else {
+            None
+        }
+    }
+
+    /// The current date in UTC
+    pub fn today() -> Date {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Date::from_days((secs / 86400) as i64)
+    }
+
+    /// The date `days` after the unix epoch
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    pub fn from_days(days: i64) -> Date {
+        let z = days + 719_468;
+        let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Date {year, month, day}
+    }
+
+    /// Days since the unix epoch
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    pub fn days(&self) -> i64 {
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = (if year >= 0 { year } else { year - 399 }) / 400;
+        let yoe = year - era * 400;
+        let mp = if self.month > 2 { self.month - 3 } else { self.month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+        era * 146_097 + doe - 719_468
+    }
+
+    /// The date `days` after this date
+    pub fn add_days(&self, days: i64) -> Date {
+        Date::from_days(self.days() + days)
+    }
+}
+
+impl FromStr for Date {
+    type Err = &'static str;
+
+    /// Parse a `YYYY-MM-DD` date
+    fn from_str(s: &str) -> Result<Date, Self::Err> {
+        let invalid = "not a YYYY-MM-DD date";
+
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+            return Err(invalid);
+        }
+
+        let mut numbers = parts.iter()
+            .map(|p| if p.bytes().all(|b| b.is_ascii_digit()) { p.parse().ok() } else { None });
+
+        match (numbers.next(), numbers.next(), numbers.next()) {
+            (Some(Some(year)), Some(Some(month)), Some(Some(day))) =>
+                Date::new(year, month, day).ok_or(invalid),
+            _ => Err(invalid)
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!{f, "{:04}-{:02}-{:02}", self.year, self.month, self.day}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_roundtrip() {
+        assert_eq!{Date::from_days(0), Date::new(1970, 1, 1).unwrap()};
+        assert_eq!{Date::new(2020, 2, 29).unwrap().days(), 18321};
+        assert_eq!{Date::new(2020, 2, 28).unwrap().add_days(2), Date::new(2020, 3, 1).unwrap()};
+    }
+
+    #[test]
+    fn parse_validates_dates() {
+        assert_eq!{"2021-06-01".parse::<Date>().unwrap().to_string(), "2021-06-01"};
+        assert!{"2021-02-29".parse::<Date>().is_err()};
+        assert!{"2021-6-1".parse::<Date>().is_err()};
+        assert!{"2021-06-01T00:00:00".parse::<Date>().is_err()};
+    }
+}
@@ -0,0 +1,474 @@
+//! Coffer files encrypted for multiple recipients
+//!
+//! A random data key encrypts the coffer with a
+//! [secret box](https://download.libsodium.org/doc/secret-key_cryptography/secretbox).
+//! The data key is sealed to the public key of each recipient. Any recipient
+//! can open the envelope with its `Certificate`.
+//!
+//! # Format
+//! An envelope starts with the magic bytes `COFFER` and a format version byte.
+//! A big endian u32 length and a [cbor](https://cbor.io/) `Header` follow,
+//! describing the payload format, its compression and the recipients. The rest
+//! of the file is the encrypted payload.
+//!
+//! Coffer files sealed directly for a single certificate, without a header, are
+//! still supported.
+//!
+//! # Signatures
+//! Envelopes can be signed with a [`SigningKey`](crate::signing::SigningKey).
+//! Signatures cover the header and the encrypted payload, so an envelope can be
+//! signed without decrypting it. Signatures are part of the header but not
+//! covered by each other.
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use flate2::{Compression as Level, read::DeflateDecoder, write::DeflateEncoder};
+use quick_error::quick_error;
+use serde::{Serialize, Deserialize};
+use sodiumoxide::crypto::secretbox;
+
+use crate::certificate::{fingerprint, Certificate, CertificateError, PublicCertificate};
+use crate::signing::{PublicSigningKey, SigningKey};
+
+/// Magic bytes at the start of every envelope
+pub const MAGIC: &[u8] = b"COFFER";
+/// Current envelope format version
+pub const VERSION: u8 = 1;
+
+/// Payload format of coffer files
+pub const PAYLOAD_TOML: &str = "toml";
+/// Deflate compression of the payload
+pub const COMPRESSION_DEFLATE: &str = "deflate";
+/// Largest decompressed payload, guards against deflate bombs
+pub const MAX_PAYLOAD: u64 = 64 * 1024 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum EnvelopeError {
+        Truncated {
+            display("Envelope is truncated")
+        }
+        Version(version: u8) {
+            display("Unsupported envelope version {}, supported is {}", version, VERSION)
+        }
+        Header(err: serde_cbor::Error) {
+            from()
+            display("Invalid envelope header: {}", err)
+        }
+        Payload(format: String) {
+            display("Unsupported payload format {}", format)
+        }
+        Compression(compression: String) {
+            display("Unsupported compression {}", compression)
+        }
+        Certificate(err: CertificateError) {
+            from()
+            display("Certificate error: {:?}", err)
+        }
+        NotARecipient(fingerprint: String, recipients: Vec<String>) {
+            display("Not encrypted for certificate {}, recipients are {}", fingerprint, recipients.join(", "))
+        }
+        Crypto {
+            display("Could not decrypt")
+        }
+        Decompress(err: std::io::Error) {
+            display("Could not decompress payload: {}", err)
+        }
+        TooLarge(limit: u64) {
+            display("Decompressed payload is larger than {} bytes", limit)
+        }
+        Unsigned {
+            display("Coffer is not signed")
+        }
+        Threshold(trusted: usize, required: usize, signers: Vec<String>) {
+            display("Coffer has {} of {} required trusted signatures, signers are {}", trusted, required, signers.join(", "))
+        }
+    }
+}
+
+/// A data key sealed to a recipient
+#[derive(Serialize, Deserialize)]
+struct Recipient {
+    public_key: Vec<u8>,
+    data_key: Vec<u8>
+}
+
+/// A signature of the envelope by a signing key
+#[derive(Serialize, Deserialize)]
+struct Signature {
+    public_key: Vec<u8>,
+    signature: Vec<u8>
+}
+
+/// Envelope metadata, stored unencrypted
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    /// Format of the payload, e.g. `toml`
+    pub payload: String,
+    /// Compression of the payload before encryption, if any
+    pub compression: Option<String>,
+    recipients: Vec<Recipient>,
+    nonce: Vec<u8>,
+    #[serde(default)]
+    signatures: Vec<Signature>
+}
+
+impl Header {
+    /// Fingerprints of the recipients' public keys
+    pub fn fingerprints(&self) -> Vec<String> {
+        self.recipients.iter().map(|r| fingerprint(&r.public_key)).collect()
+    }
+}
+
+/// A message encrypted for multiple recipients
+pub struct Envelope {
+    pub version: u8,
+    pub header: Header,
+    ciphertext: Vec<u8>
+}
+
+impl Envelope {
+    /// Encrypt a toml `message` for all `recipients`, optionally compressed
+    pub fn seal(message: &[u8], recipients: &[PublicCertificate], compress: bool) -> Result<Envelope, EnvelopeError> {
+        let key = secretbox::gen_key();
+        let nonce = secretbox::gen_nonce();
+
+        let recipients = recipients.iter()
+            .map(|recipient| Ok(Recipient {
+                public_key: recipient.public_key(),
+                data_key: recipient.seal(key.as_ref())?
+            }))
+            .collect::<Result<Vec<Recipient>, EnvelopeError>>()?;
+
+        let (payload, compression) = if compress {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(message).unwrap();
+            (encoder.finish().unwrap(), Some(COMPRESSION_DEFLATE.to_string()))
+        } else {
+            (message.to_vec(), None)
+        };
+
+        let header = Header {
+            payload: PAYLOAD_TOML.to_string(),
+            compression,
+            recipients,
+            nonce: nonce.as_ref().to_vec(),
+            signatures: Vec::new()
+        };
+
+        Ok(Envelope {
+            version: VERSION,
+            header,
+            ciphertext: secretbox::seal(&payload, &nonce, &key)
+        })
+    }
+
+    /// Decrypt the message with the `certificate` of a recipient
+    pub fn open(&self, certificate: &Certificate) -> Result<Vec<u8>, EnvelopeError> {
+        if self.header.payload != PAYLOAD_TOML {
+            return Err(EnvelopeError::Payload(self.header.payload.clone()));
+        }
+        match self.header.compression.as_deref() {
+            None | Some(COMPRESSION_DEFLATE) => (),
+            Some(compression) => return Err(EnvelopeError::Compression(compression.to_string()))
+        }
+
+        let public_key = certificate.public_key();
+        let recipient = self.header.recipients.iter()
+            .find(|r| r.public_key == public_key)
+            .ok_or_else(|| EnvelopeError::NotARecipient(fingerprint(&public_key), self.header.fingerprints()))?;
+
+        let key = certificate.open(&recipient.data_key)
+            .map_err(|_| EnvelopeError::Crypto)
+            .map(|key| secretbox::Key::from_slice(&key))?
+            .ok_or(EnvelopeError::Crypto)?;
+        let nonce = secretbox::Nonce::from_slice(&self.header.nonce)
+            .ok_or(EnvelopeError::Crypto)?;
+
+        let payload = secretbox::open(&self.ciphertext, &nonce, &key)
+            .map_err(|_| EnvelopeError::Crypto)?;
+
+        if self.header.compression.is_none() {
+            return Ok(payload);
+        }
+
+        decompress(&payload, MAX_PAYLOAD)
+    }
+
+    /// The public keys of all recipients
+    pub fn recipients(&self) -> Vec<PublicCertificate> {
+        self.header.recipients.iter()
+            .filter_map(|r| PublicCertificate::from_public_key(&r.public_key))
+            .collect()
+    }
+
+    /// Sign the envelope, replacing an earlier signature by the same key
+    pub fn sign(&mut self, key: &SigningKey) {
+        let signature = Signature {
+            public_key: key.public_key(),
+            signature: key.sign(&self.signed_data())
+        };
+
+        self.header.signatures.retain(|s| s.public_key != signature.public_key);
+        self.header.signatures.push(signature);
+    }
+
+    /// Whether the envelope is signed at all
+    pub fn is_signed(&self) -> bool {
+        !self.header.signatures.is_empty()
+    }
+
+    /// Public keys of all signers, and whether their signature is valid
+    pub fn signatures(&self) -> Vec<(Vec<u8>, bool)> {
+        let data = self.signed_data();
+
+        self.header.signatures.iter()
+            .map(|s| {
+                let valid = matches!{PublicSigningKey::from_public_key(&s.public_key),
+                                     Some(key) if key.verify(&data, &s.signature)};
+                (s.public_key.clone(), valid)
+            })
+            .collect()
+    }
+
+    /// Public keys of all signers with a valid signature, each only once
+    ///
+    /// Signature entries are not covered by signatures, so anyone can copy
+    /// them.
+    pub fn signers(&self) -> Vec<Vec<u8>> {
+        let mut seen = HashSet::new();
+
+        self.signatures().into_iter()
+            .filter(|(public_key, valid)| *valid && seen.insert(public_key.clone()))
+            .map(|(public_key, _)| public_key)
+            .collect()
+    }
+
+    /// Verify the envelope has valid signatures by at least `required` of the
+    /// `trusted` keys
+    pub fn verify(&self, trusted: &[PublicSigningKey], required: usize) -> Result<(), EnvelopeError> {
+        let signers = self.signers();
+        if signers.is_empty() {
+            return Err(EnvelopeError::Unsigned);
+        }
+
+        let trusted_signers = signers.iter()
+            .filter(|signer| trusted.iter().any(|t| t.public_key() == **signer))
+            .count();
+
+        if trusted_signers >= required {
+            Ok(())
+        } else {
+            Err(EnvelopeError::Threshold(trusted_signers, required, signers.iter().map(|s| fingerprint(s)).collect()))
+        }
+    }
+
+    /// Everything covered by signatures: the header without signatures and the
+    /// encrypted payload
+    fn signed_data(&self) -> Vec<u8> {
+        let header = &self.header;
+        serde_cbor::to_vec(&(self.version, &header.payload, &header.compression, &header.recipients,
+                             &header.nonce, &self.ciphertext)).unwrap()
+    }
+
+    /// Whether `bytes` start like an envelope
+    pub fn is_envelope(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Read an envelope, validating its header
+    pub fn from_bytes(bytes: &[u8]) -> Result<Envelope, EnvelopeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(EnvelopeError::Truncated);
+        }
+        let rest = &bytes[MAGIC.len()..];
+
+        let (&version, rest) = rest.split_first().ok_or(EnvelopeError::Truncated)?;
+        if version != VERSION {
+            return Err(EnvelopeError::Version(version));
+        }
+
+        if rest.len() < 4 {
+            return Err(EnvelopeError::Truncated);
+        }
+        let (len, rest) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+
+        if rest.len() < len {
+            return Err(EnvelopeError::Truncated);
+        }
+        let (header, ciphertext) = rest.split_at(len);
+
+        Ok(Envelope {
+            version,
+            header: serde_cbor::from_slice(header)?,
+            ciphertext: ciphertext.to_vec()
+        })
+    }
+
+    /// Serialize an envelope with its header
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EnvelopeError> {
+        let header = serde_cbor::to_vec(&self.header)?;
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 5 + header.len() + self.ciphertext.len());
+        bytes.extend(MAGIC);
+        bytes.push(self.version);
+        bytes.extend(&(header.len() as u32).to_be_bytes());
+        bytes.extend(header);
+        bytes.extend(&self.ciphertext);
+
+        Ok(bytes)
+    }
+}
+
+/// Inflate a payload of at most `limit` bytes
+fn decompress(payload: &[u8], limit: u64) -> Result<Vec<u8>, EnvelopeError> {
+    let mut message = Vec::new();
+    DeflateDecoder::new(payload).take(limit + 1).read_to_end(&mut message)
+        .map_err(EnvelopeError::Decompress)?;
+
+    if message.len() as u64 > limit {
+        return Err(EnvelopeError::TooLarge(limit));
+    }
+
+    Ok(message)
+}
+
+/// Open an encrypted coffer file with `certificate`
+///
+/// Coffers without envelope header are opened as sealed box.
+pub fn open(coffer: &[u8], certificate: &Certificate) -> Result<Vec<u8>, EnvelopeError> {
+    if Envelope::is_envelope(coffer) {
+        Envelope::from_bytes(coffer)?.open(certificate)
+    } else {
+        debug!{"Opening coffer without envelope as sealed box"}
+        Ok(certificate.open(coffer)?)
+    }
+}
+
+/// Verify the encrypted `coffer` is signed by at least `required` of the
+/// `trusted` keys
+///
+/// Coffers without envelope header can't be signed.
+pub fn verify(coffer: &[u8], trusted: &[PublicSigningKey], required: usize) -> Result<(), EnvelopeError> {
+    if !Envelope::is_envelope(coffer) {
+        return Err(EnvelopeError::Unsigned);
+    }
+
+    Envelope::from_bytes(coffer)?.verify(trusted, required)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_opens_for_all_recipients() {
+        let (a, b, c) = (Certificate::new().unwrap(), Certificate::new().unwrap(), Certificate::new().unwrap());
+        let recipients = [a.public_certificate(), b.public_certificate()];
+
+        for compress in &[false, true] {
+            let sealed = Envelope::seal(b"secret", &recipients, *compress).unwrap().to_bytes().unwrap();
+
+            assert_eq!{open(&sealed, &a).unwrap(), b"secret"};
+            assert_eq!{open(&sealed, &b).unwrap(), b"secret"};
+            assert!{matches!{open(&sealed, &c), Err(EnvelopeError::NotARecipient(..))}};
+        }
+    }
+
+    #[test]
+    fn open_accepts_sealed_boxes() {
+        let certificate = Certificate::new().unwrap();
+        let sealed = certificate.seal(b"secret").unwrap();
+
+        assert_eq!{open(&sealed, &certificate).unwrap(), b"secret"};
+    }
+
+    #[test]
+    fn decompress_is_limited() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(&[0; 1025]).unwrap();
+        let payload = encoder.finish().unwrap();
+
+        assert_eq!{decompress(&payload, 1025).unwrap().len(), 1025};
+        assert!{matches!{decompress(&payload, 1024), Err(EnvelopeError::TooLarge(1024))}};
+    }
+
+    #[test]
+    fn from_bytes_validates_header() {
+        let certificate = Certificate::new().unwrap();
+        let sealed = Envelope::seal(b"secret", &[certificate.public_certificate()], false).unwrap()
+            .to_bytes().unwrap();
+
+        let mut version = sealed.clone();
+        version[MAGIC.len()] = 2;
+        assert!{matches!{open(&version, &certificate), Err(EnvelopeError::Version(2))}};
+
+        assert!{matches!{open(&sealed[..MAGIC.len() + 3], &certificate), Err(EnvelopeError::Truncated)}};
+    }
+
+    #[test]
+    fn verify_requires_trusted_signature() {
+        let certificate = Certificate::new().unwrap();
+        let (trusted, untrusted) = (SigningKey::new().unwrap(), SigningKey::new().unwrap());
+        let trusted_keys = [trusted.public_signing_key()];
+
+        let mut envelope = Envelope::seal(b"secret", &[certificate.public_certificate()], false).unwrap();
+        assert!{matches!{envelope.verify(&trusted_keys, 1), Err(EnvelopeError::Unsigned)}};
+
+        envelope.sign(&untrusted);
+        assert!{matches!{envelope.verify(&trusted_keys, 1), Err(EnvelopeError::Threshold(0, 1, _))}};
+
+        envelope.sign(&trusted);
+        let sealed = envelope.to_bytes().unwrap();
+        assert!{verify(&sealed, &trusted_keys, 1).is_ok()};
+
+        // tampering with the payload invalidates signatures
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!{matches!{verify(&tampered, &trusted_keys, 1), Err(EnvelopeError::Unsigned)}};
+    }
+
+    #[test]
+    fn verify_counts_distinct_trusted_signers() {
+        let certificate = Certificate::new().unwrap();
+        let maintainers = [SigningKey::new().unwrap(), SigningKey::new().unwrap(), SigningKey::new().unwrap()];
+        let trusted_keys: Vec<PublicSigningKey> = maintainers.iter().map(|m| m.public_signing_key()).collect();
+
+        let mut envelope = Envelope::seal(b"secret", &[certificate.public_certificate()], false).unwrap();
+        envelope.sign(&maintainers[0]);
+        envelope.sign(&maintainers[0]);
+        assert!{matches!{envelope.verify(&trusted_keys, 2), Err(EnvelopeError::Threshold(1, 2, _))}};
+
+        // cosigning works on the serialized envelope, without decrypting it
+        let mut cosigned = Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        cosigned.sign(&maintainers[2]);
+        assert!{cosigned.verify(&trusted_keys, 2).is_ok()};
+        assert_eq!{cosigned.open(&certificate).unwrap(), b"secret"};
+    }
+
+    #[test]
+    fn verify_ignores_copied_signatures() {
+        let certificate = Certificate::new().unwrap();
+        let maintainers = [SigningKey::new().unwrap(), SigningKey::new().unwrap()];
+        let trusted_keys: Vec<PublicSigningKey> = maintainers.iter().map(|m| m.public_signing_key()).collect();
+
+        let mut envelope = Envelope::seal(b"secret", &[certificate.public_certificate()], false).unwrap();
+        envelope.sign(&maintainers[0]);
+
+        // copying a signature entry needs no key
+        let copy = Signature {
+            public_key: envelope.header.signatures[0].public_key.clone(),
+            signature: envelope.header.signatures[0].signature.clone()
+        };
+        envelope.header.signatures.push(copy);
+
+        let sealed = envelope.to_bytes().unwrap();
+        assert!{matches!{verify(&sealed, &trusted_keys, 2), Err(EnvelopeError::Threshold(1, 2, _))}};
+        assert_eq!{Envelope::from_bytes(&sealed).unwrap().signers().len(), 1};
+    }
+}
@@ -0,0 +1,127 @@
+//! Generated values in coffer definitions
+//!
+//! Instead of a value, a coffer definition can declare how to generate it
+//! ```toml
+//!   [app]
+//!   id = "1"
+//!   password = { generate = "alnum:32" }
+//! ```
+//!
+//! Supported generators are
+//! - `alnum:N`: N random ASCII letters and digits
+//! - `hex:N`: N random bytes, hex encoded
+//! - `bytes:N`: N random bytes, base64 encoded
+//!
+//! Generated values can have [metadata](crate::coffer#metadata) like other
+//! values, e.g. `{ generate = "alnum:32", owner = "ops" }`.
+//!
+//! Generated values are materialized once into the coffer definition, so they
+//! stay stable. A coffer with unmaterialized values is invalid.
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use sodiumoxide::randombytes::{randombytes, randombytes_uniform};
+use toml::Value as TomlValue;
+
+use crate::coffer::{table_path, CofferError, CofferResult};
+
+const ALNUM: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Generate a value according to `spec`. `None` if `spec` is invalid.
+pub fn generate(spec: &str) -> Option<String> {
+    let mut parts = spec.splitn(2, ':');
+    let kind = parts.next()?;
+    let len: usize = parts.next()?.parse().ok()?;
+
+    match kind {
+        "alnum" => Some((0..len)
+                        .map(|_| ALNUM[randombytes_uniform(ALNUM.len() as u32) as usize] as char)
+                        .collect()),
+        "hex" => Some(hex::encode(randombytes(len))),
+        "bytes" => Some(base64::encode(&randombytes(len))),
+        _ => None
+    }
+}
+
+/// The generator spec of `value`, if it is a generated value
+pub fn generator(value: &TomlValue) -> Option<&TomlValue> {
+    value.as_table().and_then(|table| table.get("generate"))
+}
+
+/// Replace generated values in a toml coffer `document` by generated strings
+///
+/// Returns the number of materialized values.
+pub fn materialize(document: &mut toml::value::Table) -> CofferResult<usize> {
+    materialize_table("", document)
+}
+
+fn materialize_table(path: &str, table: &mut toml::value::Table) -> CofferResult<usize> {
+    let shard = table.contains_key("id");
+    let mut count = 0;
+
+    for (key, value) in table.iter_mut() {
+        let location = table_path(path, key);
+
+        if let Some(spec) = generator(value) {
+            if !shard { continue } // leave invalid definitions to the coffer parser
+
+            let generated = spec.as_str()
+                .and_then(generate)
+                .ok_or_else(|| CofferError::Invalid(location.clone(), format!{"invalid generator {}", spec}))?;
+
+            debug!{"Generated value for {}", location}
+            let generated = TomlValue::String(generated);
+
+            // keep metadata, the generated value becomes the table's value
+            match value {
+                TomlValue::Table(table) if table.len() > 1 => {
+                    table.remove("generate");
+                    table.insert("value".to_string(), generated);
+                }
+                value => *value = generated
+            }
+            count += 1;
+        } else if let TomlValue::Table(subtable) = value {
+            if !shard { count += materialize_table(&location, subtable)? }
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_follows_spec() {
+        let alnum = generate("alnum:32").unwrap();
+        assert_eq!{alnum.len(), 32};
+        assert!{alnum.chars().all(|c| c.is_ascii_alphanumeric())};
+
+        assert_eq!{hex::decode(generate("hex:16").unwrap()).unwrap().len(), 16};
+        assert_eq!{base64::decode(&generate("bytes:64").unwrap()).unwrap().len(), 64};
+    }
+
+    #[test]
+    fn generate_rejects_invalid_specs() {
+        assert!{generate("alnum").is_none()};
+        assert!{generate("alnum:x").is_none()};
+        assert!{generate("words:4").is_none()};
+    }
+
+    #[test]
+    fn materialize_replaces_generated_values() {
+        let mut document = r#"
+            [app.frontend]
+            id = "1"
+            user = "app"
+            password = { generate = "alnum:12" }
+        "#.parse::<TomlValue>().unwrap().try_into().unwrap();
+
+        assert_eq!{materialize(&mut document).unwrap(), 1};
+
+        let password = &document["app"]["frontend"]["password"];
+        assert_eq!{password.as_str().map(str::len), Some(12)};
+    }
+}
@@ -4,6 +4,7 @@ use log::{debug, error, info, trace, warn};
 
 use std::path::Path;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use quick_error::quick_error;
 use sodiumoxide::crypto::box_;
@@ -12,6 +13,8 @@ use sodiumoxide::crypto::sealedbox;
 use toml::Value as TomlValue;
 
 use crate::certificate::{Certificate, CertificateError};
+use crate::coffer::table_path;
+use crate::envelope::{self, EnvelopeError};
 
 quick_error! {
     #[derive(Debug)]
@@ -21,9 +24,20 @@ quick_error! {
         Certificate(err: CertificateError) {
             from()
         }
+        Envelope(err: EnvelopeError) {
+            from()
+            display("{}", err)
+        }
         HexDecodeError(err: hex::FromHexError) {
             from()
         }
+        Toml(err: toml::de::Error) {
+            from()
+            display("Invalid toml: {}", err)
+        }
+        Invalid(location: String, reason: &'static str) {
+            display("Invalid definition of {}: {}", location, reason)
+        }
         IoError(err: std::io::Error) {
             from()
         }
@@ -42,15 +56,22 @@ quick_error! {
 /// A keyring constists of the owner's certificate and
 /// the known and trusted public keys of the keyring owner
 pub struct Keyring {
-    certificate: Certificate,
-    known_keys: HashMap<Vec<u8>, box_::PublicKey>
+    certificate: Arc<Certificate>,
+    known_keys: HashMap<Vec<u8>, KnownKey>
+}
+
+/// A known and trusted public key with a human-readable name
+#[derive(Debug)]
+struct KnownKey {
+    name: String,
+    public_key: box_::PublicKey
 }
 
 impl Keyring {
   /// Create a new keyring
     pub fn new(certificate: Certificate) -> Keyring {
         Keyring {
-            certificate,
+            certificate: Arc::new(certificate),
             known_keys: HashMap::new()
         }
     }
@@ -60,7 +81,16 @@ impl Keyring {
     where T: AsRef<Path>
     {
         Keyring {
-            certificate: Certificate::new_from_cbor(certificate_path).unwrap(),
+            certificate: Arc::new(Certificate::new_from_cbor(certificate_path).unwrap()),
+            known_keys: HashMap::new()
+        }
+    }
+
+    /// A keyring with the same certificate but no known keys, e.g. for
+    /// reloading the known keys
+    pub fn without_known_keys(&self) -> Keyring {
+        Keyring {
+            certificate: self.certificate.clone(),
             known_keys: HashMap::new()
         }
     }
@@ -69,49 +99,68 @@ impl Keyring {
     // TODO: This needs to be refactored. Keyring shouldn't be that tightly bound to coffer format
     pub fn add_known_keys_toml(&mut self, toml: &str) -> Result<(), KeyringError> {
         // parse the string into a toml Table
-        let clients: toml::value::Table = match toml.parse::<TomlValue>().unwrap() {
+        let clients: toml::value::Table = match toml.parse::<TomlValue>()? {
             TomlValue::Table(t) => t,
-            _ => panic!{"Invalid secrets file"}
+            _ => return Err(KeyringError::Msg("Invalid secrets file"))
         };
 
-        self.add_known_keys_toml_table(&clients)?;
+        self.add_known_keys_toml_table("", &clients)?;
 
         debug!{"Known keys {:?}", self.known_keys}
 
         Ok(())
     }
 
-    fn add_known_keys_toml_table(&mut self, toml_table: &toml::value::Table) -> Result<(), KeyringError> {
+    fn add_known_keys_toml_table(&mut self, path: &str, toml_table: &toml::value::Table) -> Result<(), KeyringError> {
          // table has an no id, recourse into subtables
         if toml_table.get("id").is_none() {
             debug!{"{:?}", toml_table}
-            for (_key, val) in toml_table.iter() {
+            for (key, val) in toml_table.iter() {
                 match val {
                     TomlValue::Table(subtable) => {
-                        self.add_known_keys_toml_table(subtable)?;
+                        self.add_known_keys_toml_table(&table_path(path, key), subtable)?;
                     },
-                    _ => panic!{"Invalid secrets file"}
+                    _ => return Err(KeyringError::Invalid(table_path(path, key), "value outside of a shard"))
                 }
             }
 
             return Ok(());
         }
 
-        let shard = toml_table.get("id").and_then(|id| id.as_str()).ok_or(KeyringError::Msg("Invalid key parsing state"))?;
-        self.add_known_key(&hex::decode(shard)?)
+        if path.is_empty() {
+            return Err(KeyringError::Invalid("id".to_string(), "shards must be defined in a table"));
+        }
+        let shard = toml_table.get("id").and_then(|id| id.as_str())
+            .ok_or_else(|| KeyringError::Invalid(path.to_string(), "id must be a string"))?;
+        self.add_known_key(path, decode_public_key(shard)?.as_ref())
     }
 
-    pub fn add_known_key(&mut self, key: &[u8]) -> Result<(), KeyringError> {
+    /// Add `key` as known key to the keyring. `name` is used for referring to
+    /// the key in human-readable output.
+    pub fn add_known_key(&mut self, name: &str, key: &[u8]) -> Result<(), KeyringError> {
         let public_key = box_::PublicKey::from_slice(key)
             .ok_or(KeyringError::InvalidClientKey)?;
 
-        self.known_keys.insert(Vec::from(key), public_key);
+        let name = name.to_string();
+        self.known_keys.insert(Vec::from(key), KnownKey{name, public_key});
         Ok(())
     }
 
-    /// Open a sealed message with the keyring owner's certificate
+    /// Retrieve the name of a known key. `None` if `key` is not known.
+    pub fn get_known_key_name(&self, key: &[u8]) -> Option<&str> {
+        self.known_keys.get(key)
+            .map(|known_key| known_key.name.as_str())
+    }
+
+    /// Iterate over the names and public keys of all known keys
+    pub fn known_keys(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.known_keys.iter()
+            .map(|(key, known_key)| (known_key.name.as_str(), key.as_slice()))
+    }
+
+    /// Open a coffer encrypted for the keyring owner's certificate
     pub fn open(&self, message: &[u8]) -> Result<Vec<u8>, KeyringError> {
-        self.certificate.open(message)
+        envelope::open(message, &self.certificate)
             .map_err(KeyringError::from)
     }
 
@@ -128,6 +177,31 @@ impl Keyring {
         let client_key = self.known_keys.get(client)
             .ok_or(KeyringError::UnkownClientKey)?;
 
-        Ok(sealedbox::seal(message, client_key))
+        Ok(sealedbox::seal(message, &client_key.public_key))
+    }
+}
+
+/// Decode a hex encoded public key as used for shard ids
+pub fn decode_public_key(id: &str) -> Result<box_::PublicKey, KeyringError> {
+    box_::PublicKey::from_slice(&hex::decode(id)?)
+        .ok_or(KeyringError::InvalidClientKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_error(toml: &str) -> String {
+        let mut keyring = Keyring::new(Certificate::new().unwrap());
+        keyring.add_known_keys_toml(toml).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn add_known_keys_toml_reports_invalid_files() {
+        assert!{add_error("[app").starts_with("Invalid toml: ")};
+        assert_eq!{add_error("a = 1"), "Invalid definition of a: value outside of a shard"};
+        assert_eq!{add_error("[app]\nb.c = 1"), "Invalid definition of app.b.c: value outside of a shard"};
+        assert_eq!{add_error("[app]\nid = 1"), "Invalid definition of app: id must be a string"};
+        assert_eq!{add_error("id = \"AA\""), "Invalid definition of id: shards must be defined in a table"};
     }
 }
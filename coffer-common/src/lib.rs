@@ -2,4 +2,11 @@
 
 pub mod certificate;
 pub mod coffer;
+pub mod coffer_map;
+pub mod date;
+pub mod envelope;
+pub mod generate;
 pub mod keyring;
+pub mod signing;
+pub mod status;
+mod reference;
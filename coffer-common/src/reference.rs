@@ -0,0 +1,310 @@
+//! Resolution of references between coffer values
+//!
+//! See the [coffer module](crate::coffer#references) for the reference syntax.
+//!
+//! A resolved value expires with the earliest expiring value it references,
+//! its `not_after` metadata is set accordingly.
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::collections::HashMap;
+
+use crate::coffer::{Coffer, CofferError, CofferKey, CofferResult, CofferValue};
+use crate::date::Date;
+
+/// Part of a string value
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Reference(String)
+}
+
+/// Split a string value into literals and references
+fn parse(value: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        // `$${` escapes a literal `${`
+        if rest[..start].ends_with('$') {
+            literal.push_str(&rest[..start - 1]);
+            literal.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        literal.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| format!{"unterminated reference in \"{}\"", value})?;
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(Part::Reference(rest[start + 2..start + end].to_string()));
+        rest = &rest[start + end + 1..];
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+struct Resolver<'a, C: Coffer> {
+    coffer: &'a C,
+    /// Shard ids by shard name
+    shards: HashMap<String, String>,
+    resolved: HashMap<CofferKey, CofferValue>,
+    /// Earliest expiry of resolved values and the values they reference
+    not_after: HashMap<CofferKey, Date>,
+    /// Keys currently being resolved, for detecting cycles
+    visiting: Vec<CofferKey>
+}
+
+impl<'a, C: Coffer> Resolver<'a, C> {
+    fn new(coffer: &'a C) -> Self {
+        let shards = coffer.get_shard_ids().iter()
+            .filter_map(|id| coffer.get_shard_name(id).map(|name| (name, id.clone())))
+            .collect();
+
+        Resolver {
+            coffer,
+            shards,
+            resolved: HashMap::new(),
+            not_after: HashMap::new(),
+            visiting: Vec::new()
+        }
+    }
+
+    /// Human-readable location of `key` for errors
+    fn location(&self, key: &CofferKey) -> String {
+        let shard = self.coffer.get_shard_name(&key.shard)
+            .unwrap_or_else(|| key.shard.clone());
+        format!{"{}.{}", shard, key.key}
+    }
+
+    fn error(&self, key: &CofferKey, reason: String) -> CofferError {
+        CofferError::Reference(self.location(key), reason)
+    }
+
+    /// Find the `CofferKey` `reference` in `shard` points to
+    fn target(&self, shard: &str, reference: &str) -> Result<CofferKey, String> {
+        let own = CofferKey{shard: shard.to_string(), key: reference.to_string()};
+        let dots: Vec<usize> = reference.match_indices('.').map(|(dot, _)| dot).collect();
+        let last = match dots.last() {
+            Some(&dot) => dot,
+            None => return Ok(own)
+        };
+
+        // keys can contain dots too, find the existing values it refers to
+        let mut candidates: Vec<(&str, CofferKey)> = dots.iter()
+            .filter_map(|&dot| {
+                let name = &reference[..dot];
+                self.shards.get(name)
+                    .map(|target| (name, CofferKey{shard: target.clone(), key: reference[dot + 1..].to_string()}))
+            })
+            .filter(|(_, key)| self.coffer.get(key).is_some())
+            .collect();
+        if self.coffer.get(&own).is_some() {
+            candidates.push(("", own));
+        }
+
+        let (name, key) = match candidates.len() {
+            0 => {
+                // the unknown shard or value is reported
+                let name = &reference[..last];
+                let target = self.shards.get(name)
+                    .ok_or_else(|| format!{"unknown shard {} in ${{{}}}", name, reference})?;
+                (name, CofferKey{shard: target.clone(), key: reference[last + 1..].to_string()})
+            },
+            1 => candidates.pop().unwrap(),
+            _ => {
+                let values: Vec<String> = candidates.iter().map(|(_, key)| self.location(key)).collect();
+                return Err(format!{"ambiguous reference ${{{}}}, could be {}", reference, values.join(" or ")});
+            }
+        };
+        if key.shard != shard && !self.coffer.get_shard_reads(shard).iter().any(|r| r == name) {
+            return Err(format!{"shard {} is not readable", name});
+        }
+
+        Ok(key)
+    }
+
+    /// Last date `key` is valid, including the values it references once
+    /// resolved
+    fn not_after(&self, key: &CofferKey) -> Option<Date> {
+        self.not_after.get(key).copied()
+            .or_else(|| self.coffer.get_meta(key).and_then(|meta| meta.not_after))
+    }
+
+    fn resolve(&mut self, key: &CofferKey) -> CofferResult<CofferValue> {
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(value.clone());
+        }
+
+        if let Some(pos) = self.visiting.iter().position(|k| k == key) {
+            let cycle: Vec<String> = self.visiting[pos..].iter()
+                .chain(std::iter::once(key))
+                .map(|k| self.location(k))
+                .collect();
+            return Err(self.error(key, format!{"cyclic reference {}", cycle.join(" -> ")}));
+        }
+
+        let value = match self.coffer.get(key) {
+            Some(CofferValue::String(s)) => s,
+            Some(value) => return Ok(value),
+            None => unreachable!{"Resolving unknown key {:?}", key}
+        };
+
+        let parts = parse(&value).map_err(|reason| self.error(key, reason))?;
+
+        self.visiting.push(key.clone());
+        let mut not_after = self.not_after(key);
+        let mut values = Vec::with_capacity(parts.len());
+        for part in &parts {
+            match part {
+                Part::Literal(l) => values.push(CofferValue::String(l.clone())),
+                Part::Reference(r) => {
+                    let target = self.target(&key.shard, r)
+                        .map_err(|reason| self.error(key, reason))?;
+                    if self.coffer.get(&target).is_none() {
+                        return Err(self.error(key, format!{"unknown value ${{{}}}", r}));
+                    }
+                    values.push(self.resolve(&target)?);
+
+                    not_after = match (not_after, self.not_after(&target)) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b)
+                    };
+                }
+            }
+        }
+        self.visiting.pop();
+
+        // a single reference keeps the type of the referenced value
+        let value = match (parts.as_slice(), values.as_slice()) {
+            ([Part::Reference(_)], [value]) => value.clone(),
+            _ => CofferValue::String(values.iter().map(|v| v.to_string()).collect())
+        };
+
+        if let Some(not_after) = not_after {
+            self.not_after.insert(key.clone(), not_after);
+        }
+        self.resolved.insert(key.clone(), value.clone());
+        Ok(value)
+    }
+}
+
+/// Resolve all references in `coffer`
+pub fn resolve<C: Coffer>(coffer: &mut C) -> CofferResult<()> {
+    let ids = coffer.get_shard_ids();
+    let mut resolver = Resolver::new(&*coffer);
+    let mut errors = Vec::new();
+
+    for id in &ids {
+        for read in coffer.get_shard_reads(id) {
+            if !resolver.shards.contains_key(&read) {
+                let name = coffer.get_shard_name(id).unwrap_or_else(|| id.clone());
+                errors.push(CofferError::Reference(name, format!{"reads unknown shard {}", read}));
+            }
+        }
+    }
+
+    for id in &ids {
+        let shard = resolver.coffer.get_shard(id).map(|s| s.0).unwrap_or_default();
+        for (key, _) in shard {
+            if let Err(err) = resolver.resolve(&CofferKey{shard: id.clone(), key}) {
+                errors.push(err);
+                resolver.visiting.clear();
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(CofferError::Errors(errors));
+    }
+
+    let (resolved, not_after) = (resolver.resolved, resolver.not_after);
+    debug!{"Resolved {} values", resolved.len()}
+
+    for (key, value) in resolved {
+        coffer.push(key, value);
+    }
+
+    // expire with referenced values
+    for (key, not_after) in not_after {
+        let mut meta = coffer.get_meta(&key).unwrap_or_default();
+        if meta.not_after != Some(not_after) {
+            meta.not_after = Some(not_after);
+            coffer.put_meta(key, meta);
+        }
+    }
+
+    Ok(())
+}
+
+/// The values each value in `coffer` references directly
+///
+/// Values without references are included with no targets.
+pub fn references<C: Coffer>(coffer: &C) -> CofferResult<HashMap<CofferKey, Vec<CofferKey>>> {
+    let resolver = Resolver::new(coffer);
+    let mut references = HashMap::new();
+    let mut errors = Vec::new();
+
+    for id in coffer.get_shard_ids() {
+        for (key, value) in coffer.get_shard(&id).map(|s| s.0).unwrap_or_default() {
+            let key = CofferKey{shard: id.clone(), key};
+
+            let parts = match value {
+                CofferValue::String(s) => parse(&s).map_err(|reason| resolver.error(&key, reason)),
+                _ => Ok(Vec::new())
+            };
+
+            let targets = parts.and_then(|parts| parts.iter()
+                .filter_map(|part| match part {
+                    Part::Reference(r) => Some(resolver.target(&id, r).map_err(|reason| resolver.error(&key, reason))),
+                    Part::Literal(_) => None
+                })
+                .collect::<CofferResult<Vec<CofferKey>>>());
+
+            match targets {
+                Ok(targets) => { references.insert(key, targets); }
+                Err(err) => errors.push(err)
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(CofferError::Errors(errors));
+    }
+
+    Ok(references)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_splits_references() {
+        assert_eq!{parse("a${b}c${d.e}").unwrap(), vec![
+            Part::Literal("a".into()),
+            Part::Reference("b".into()),
+            Part::Literal("c".into()),
+            Part::Reference("d.e".into())
+        ]};
+    }
+
+    #[test]
+    fn parse_unescapes_literal_references() {
+        assert_eq!{parse("a$${b}").unwrap(), vec![Part::Literal("a${b}".into())]};
+    }
+
+    #[test]
+    fn parse_rejects_unterminated_references() {
+        assert!{parse("a${b").is_err()};
+    }
+}
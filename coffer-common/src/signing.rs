@@ -0,0 +1,146 @@
+//! Signing keys for authenticating coffers
+//!
+//! Coffers are signed by the [ed25519](https://ed25519.cr.yp.to/) signing key
+//! of coffer-companion. coffer-server only needs the public keys of trusted
+//! signers.
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::{
+    path::Path,
+    io::BufReader,
+    fs::File,
+};
+
+#[allow(unused_imports)]
+use std::ops::Deref; // we use this but rustc doesn't know
+
+use seckey::SecKey;
+use sodiumoxide::crypto::sign;
+use serde::{Serialize, Deserialize};
+
+use crate::certificate::CertificateError;
+
+/// A secure container for a signing keypair
+///
+/// Provides the same best effort guarantees as a
+/// [`Certificate`](crate::certificate::Certificate).
+pub struct SigningKey {
+    inner: SecKey<SigningKeyInner>
+}
+
+// SigningKey and its inner SecKey own their raw pointer without any thread
+// local behaviour
+unsafe impl Send for SigningKey {}
+// After initialization, the signing key is read-only
+unsafe impl Sync for SigningKey {}
+
+#[derive(Serialize, Deserialize)]
+struct SigningKeyInner {
+    public_key: sign::PublicKey,
+    secret_key: sign::SecretKey
+}
+
+impl SigningKey {
+    /// Initialize with a generated keypair
+    pub fn new() -> Result<SigningKey, CertificateError> {
+        debug!{"Generating new signing key"}
+        let (public_key, secret_key) = sign::gen_keypair();
+
+        let inner = SecKey::new(SigningKeyInner{public_key, secret_key})
+            .map_err(|_| CertificateError::SecKey)?;
+
+        Ok(SigningKey{inner})
+    }
+
+    /// Initialize from a serialized signing key in [cbor](https://cbor.io/) format
+    pub fn new_from_cbor<T: AsRef<Path>>(path: T) -> Result<SigningKey, CertificateError> {
+        debug!{"Reading signing key from {}", path.as_ref().display()}
+        let f = File::open(path)?;
+
+        let inner_key = serde_cbor::from_reader(BufReader::new(f))?;
+        let inner = SecKey::new(inner_key).map_err(|_| CertificateError::SecKey)?;
+
+        Ok(SigningKey{inner})
+    }
+
+    /// Serialize a signing key in [cbor](https://cbor.io/) format
+    #[cfg(feature = "export")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CertificateError> {
+        let read_guard = self.inner.read();
+
+        Ok(serde_cbor::to_vec(read_guard.deref())?)
+    }
+
+    /// Clone the bytes of the public key
+    pub fn public_key(&self) -> Vec<u8> {
+        self.inner.read().public_key.as_ref().to_owned()
+    }
+
+    /// The public part of the signing key
+    pub fn public_signing_key(&self) -> PublicSigningKey {
+        PublicSigningKey{public_key: self.inner.read().public_key}
+    }
+
+    /// Create a detached signature of `message`
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        sign::sign_detached(message, &self.inner.read().secret_key).as_ref().to_owned()
+    }
+}
+
+/// The public key of a `SigningKey`
+///
+/// Can only verify signatures. A serialized `SigningKey` can also be read as
+/// `PublicSigningKey`.
+#[derive(Serialize, Deserialize)]
+pub struct PublicSigningKey {
+    public_key: sign::PublicKey
+}
+
+impl PublicSigningKey {
+    /// Initialize from the bytes of a public key. `None` if `public_key` is
+    /// not a valid key.
+    pub fn from_public_key(public_key: &[u8]) -> Option<PublicSigningKey> {
+        sign::PublicKey::from_slice(public_key)
+            .map(|public_key| PublicSigningKey{public_key})
+    }
+
+    /// Initialize from a serialized signing key in [cbor](https://cbor.io/) format
+    pub fn new_from_cbor<T: AsRef<Path>>(path: T) -> Result<PublicSigningKey, CertificateError> {
+        debug!{"Reading public signing key from {}", path.as_ref().display()}
+        let f = File::open(path)?;
+
+        Ok(serde_cbor::from_reader(BufReader::new(f))?)
+    }
+
+    /// Serialize a public signing key in [cbor](https://cbor.io/) format
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CertificateError> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    /// Clone the bytes of the public key
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_ref().to_owned()
+    }
+
+    /// Whether `signature` is a valid signature of `message` by this key
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        matches!{sign::Signature::from_slice(signature),
+                 Some(signature) if sign::verify_detached(&signature, message, &self.public_key)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_verify_with_public_key_only() {
+        let (key, other) = (SigningKey::new().unwrap(), SigningKey::new().unwrap());
+        let signature = key.sign(b"coffer");
+
+        assert!{key.public_signing_key().verify(b"coffer", &signature)};
+        assert!{!key.public_signing_key().verify(b"coffee", &signature)};
+        assert!{!other.public_signing_key().verify(b"coffer", &signature)};
+    }
+}
@@ -0,0 +1,38 @@
+//! Status of a running coffer-server
+//!
+//! Admins request the status after their hello with a `0x10` message. The
+//! server answers with a `0x11` message containing the `ServerStatus` in
+//! [cbor](https://cbor.io/) format, sealed for the admin's public key. The
+//! status never contains secrets.
+//!
+//! Admins can also make the server reload its secrets with a `0x12` message.
+//! The server answers with a `0x14` message containing a random challenge,
+//! sealed for the admin's public key. The admin proves its key by sending the
+//! opened challenge back in a `0x15` message. Only then the server reloads and
+//! answers with a sealed `0x13` message containing the `ReloadStatus`. Reloads
+//! requested by admins are rate limited.
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerStatus {
+    /// Version of coffer-server
+    pub version: String,
+    /// Seconds since the server started
+    pub uptime: u64,
+    /// Number of loaded shards
+    pub shards: usize,
+    /// Names and public key fingerprints of the known clients
+    pub clients: Vec<(String, String)>,
+    /// Number of open connections, including the admin's
+    pub connections: usize,
+    /// Seconds since the unix epoch, when the secrets were last loaded
+    pub loaded: u64
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadStatus {
+    /// Why the secrets could not be reloaded. The server keeps the previously
+    /// loaded secrets then.
+    pub error: Option<String>
+}
@@ -0,0 +1,90 @@
+//! Onboarding of new clients
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::Coffer;
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::generate;
+
+use toml::{Value, value::Table};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::certificate::write_certificate;
+use crate::coffer_file;
+
+/// Create a client certificate at `out` and a shard `name` for it in `coffer`
+///
+/// The shard is empty or filled with the values in the `template` file.
+/// Generated values in the template are materialized. The coffer is sealed
+/// again with the server `certificate`. Returns `false` if the client could not
+/// be added.
+pub fn add_client(name: String, coffer: PathBuf, certificate: PathBuf, out: PathBuf, template: Option<PathBuf>) -> bool {
+    match add(&name, &coffer, &certificate, &out, template.as_deref()) {
+        Ok(id) => { println!{"Added client {} with id {}", name, id}; true }
+        Err(problem) => { eprintln!{"{}: {}", coffer.display(), problem}; false }
+    }
+}
+
+fn add(name: &str, path: &Path, certificate: &Path, out: &Path, template: Option<&Path>) -> Result<String, String> {
+    let server_cert = Certificate::new_from_cbor(certificate)
+        .map_err(|err| format!{"Could not read server certificate: {:?}", err})?;
+
+    let mut coffer = CofferMap::new();
+    let toml = coffer_file::read(path, Some(&server_cert))?;
+    coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+
+    let template = match template {
+        Some(template) => fs::read_to_string(template)
+            .map_err(|err| format!{"Could not read template {}: {}", template.display(), err})?,
+        None => String::new()
+    };
+
+    let client_cert = Certificate::new().unwrap();
+    let id = hex::encode_upper(client_cert.public_key());
+
+    let mut document = shard_document(name, &id, &template)?;
+    generate::materialize(&mut document).map_err(|err| err.to_string())?;
+    coffer.from_toml_table("", &document).map_err(|err| err.to_string())?;
+
+    if coffer.get_shard_name(&id).as_deref() != Some(name) {
+        return Err(format!{"Invalid client name {}", name});
+    }
+
+    write_certificate(out, &client_cert.to_cbor().unwrap())
+        .map_err(|err| format!{"Could not write certificate {}: {}", out.display(), err})?;
+
+    // a certificate without a shard is of no use
+    if let Err(problem) = coffer_file::write(path, &coffer.to_toml(), Some(&server_cert)) {
+        let _ = fs::remove_file(out);
+        return Err(problem);
+    }
+
+    Ok(id)
+}
+
+/// The toml document of the shard `name` with `id` and the values of
+/// `template`
+///
+/// Dotted names are nested tables, like in table headers of coffer files.
+pub(crate) fn shard_document(name: &str, id: &str, template: &str) -> Result<Table, String> {
+    let mut shard: Table = toml::from_str(template)
+        .map_err(|err| format!{"Invalid template: {}", err})?;
+    if shard.contains_key("id") {
+        return Err("Invalid template: the id is set for the client".to_string());
+    }
+    shard.insert("id".to_string(), Value::String(id.to_string()));
+
+    let mut document = shard;
+    for part in name.rsplit('.') {
+        if part.is_empty() {
+            return Err(format!{"Invalid client name {}", name});
+        }
+
+        let mut table = Table::new();
+        table.insert(part.to_string(), Value::Table(document));
+        document = table;
+    }
+
+    Ok(document)
+}
@@ -0,0 +1,286 @@
+//! Access reports for coffers
+//!
+//! Every shard belongs to the client whose public key is the shard id. A client
+//! reads the values of its own shard, and the values they reference, directly
+//! or through other references.
+
+use coffer_common::certificate::{fingerprint, Certificate};
+use coffer_common::coffer::{Coffer, CofferKey, CofferValue};
+use coffer_common::coffer_map::CofferMap;
+
+use serde_json::json;
+use toml::Value as TomlValue;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::coffer_file;
+
+/// How a client reads a value
+#[derive(Clone, Copy)]
+enum Access {
+    Own,
+    Reference
+}
+
+impl Access {
+    fn as_str(self) -> &'static str {
+        match self {
+            Access::Own => "own",
+            Access::Reference => "reference"
+        }
+    }
+}
+
+/// A client, identified by its shard
+struct Client {
+    name: String,
+    fingerprint: String
+}
+
+/// Print which clients read which values of the coffer at `path`
+///
+/// Encrypted coffers are opened with the server `certificate`. The report is a
+/// table or `json`. Values that are identical in several shards are
+/// flagged, they are usually better shared by a reference. Returns `false` if
+/// the coffer could not be read.
+pub fn audit(path: PathBuf, certificate: Option<PathBuf>, json: bool) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let coffer = coffer_file::read(&path, certificate.as_ref()).and_then(|toml| {
+        let mut coffer = CofferMap::new();
+        coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+        Ok(coffer)
+    });
+    let report = coffer.and_then(|coffer| {
+        let references = coffer.references().map_err(|err| err.to_string())?;
+        Ok(Report::new(&coffer, &references))
+    });
+
+    match report {
+        Ok(report) if json => println!{"{}", serde_json::to_string_pretty(&report.to_json()).unwrap()},
+        Ok(report) => print!{"{}", report.to_table()},
+        Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; return false }
+    }
+
+    true
+}
+
+/// A value, ordered by its location for display
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Value {
+    /// `shard.key` with the shard name
+    location: String,
+    shard: String,
+    key: String
+}
+
+struct Report {
+    /// Clients by shard id, ordered by name
+    clients: Vec<(String, Client)>,
+    /// Readers of each value by shard id
+    access: BTreeMap<Value, BTreeMap<String, Access>>,
+    /// Groups of values in different shards with the same value, as `shard.key`
+    duplicates: Vec<Vec<String>>
+}
+
+impl Report {
+    fn new(coffer: &CofferMap, references: &HashMap<CofferKey, Vec<CofferKey>>) -> Report {
+        let name = |id: &String| coffer.get_shard_name(id).unwrap_or_else(|| id.clone());
+        let value = |key: &CofferKey| Value {
+            location: format!{"{}.{}", name(&key.shard), key.key},
+            shard: key.shard.clone(),
+            key: key.key.clone()
+        };
+
+        let mut clients: Vec<(String, Client)> = coffer.get_shard_ids().into_iter()
+            .map(|id| {
+                let fingerprint = hex::decode(&id).map(|key| fingerprint(&key)).unwrap_or_else(|_| "-".to_string());
+                (id.clone(), Client{name: name(&id), fingerprint})
+            })
+            .collect();
+        clients.sort_by(|(a_id, a), (b_id, b)| (&a.name, a_id).cmp(&(&b.name, b_id)));
+
+        let mut access: BTreeMap<Value, BTreeMap<String, Access>> = BTreeMap::new();
+        for key in references.keys() {
+            access.entry(value(key)).or_default().insert(key.shard.clone(), Access::Own);
+
+            // everything reachable through references
+            let mut seen = HashSet::new();
+            let mut todo: Vec<&CofferKey> = references[key].iter().collect();
+            while let Some(target) = todo.pop() {
+                // unknown values can't be read
+                if !references.contains_key(target) || !seen.insert(target) { continue }
+
+                access.entry(value(target)).or_default()
+                    .entry(key.shard.clone()).or_insert(Access::Reference);
+                todo.extend(references.get(target).into_iter().flatten());
+            }
+        }
+
+        // literal values only, shared values via references are fine. Values
+        // are compared as toml, so values of different types differ.
+        let mut by_value: BTreeMap<String, BTreeSet<Value>> = BTreeMap::new();
+        for (key, targets) in references {
+            match coffer.get(key) {
+                Some(CofferValue::String(s)) if s.is_empty() => (),
+                Some(literal) if targets.is_empty() => {
+                    by_value.entry(TomlValue::from(literal).to_string()).or_default().insert(value(key));
+                },
+                _ => ()
+            }
+        }
+        let duplicates = by_value.into_iter()
+            .map(|(_, values)| values)
+            .filter(|values| values.iter().map(|v| &v.shard).collect::<BTreeSet<_>>().len() > 1)
+            .map(|values| values.into_iter().map(|v| v.location).collect())
+            .collect();
+
+        Report{clients, access, duplicates}
+    }
+
+    fn to_table(&self) -> String {
+        let mut table = String::from("Clients:\n");
+        let width = self.clients.iter().map(|(_, c)| c.name.len()).max().unwrap_or(0);
+        for (_, client) in &self.clients {
+            table.push_str(&format!{"  {:width$}  {}\n", client.name, client.fingerprint, width = width});
+        }
+
+        let width = self.access.keys().map(|v| v.location.len()).max().unwrap_or(0).max("value".len());
+
+        table.push_str(&format!{"\n{:width$}", "value", width = width});
+        for (_, client) in &self.clients {
+            table.push_str(&format!{"  {}", client.name});
+        }
+        table.push('\n');
+
+        for (value, readers) in &self.access {
+            let mut row = format!{"{:width$}", value.location, width = width};
+            for (id, client) in &self.clients {
+                let access = readers.get(id).map_or("", |a| a.as_str());
+                row.push_str(&format!{"  {:width$}", access, width = client.name.len()});
+            }
+            table.push_str(row.trim_end());
+            table.push('\n');
+        }
+
+        if !self.duplicates.is_empty() {
+            table.push_str("\nDuplicated values:\n");
+            for keys in &self.duplicates {
+                table.push_str(&format!{"  {}\n", keys.join(", ")});
+            }
+        }
+
+        table
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let names: HashMap<&String, &String> = self.clients.iter().map(|(id, c)| (id, &c.name)).collect();
+
+        json!{{
+            "clients": self.clients.iter()
+                .map(|(id, c)| json!{{"name": c.name, "id": id, "fingerprint": c.fingerprint}})
+                .collect::<Vec<_>>(),
+            "access": self.access.iter()
+                .map(|(value, readers)| json!{{
+                    "value": value.location,
+                    "shard": value.shard,
+                    "key": value.key,
+                    "readers": readers.iter()
+                        .map(|(id, access)| json!{{"id": id, "name": names.get(id), "access": access.as_str()}})
+                        .collect::<Vec<_>>()
+                }})
+                .collect::<Vec<_>>(),
+            "duplicates": self.duplicates
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(toml: &str) -> Report {
+        let coffer = CofferMap::from_toml(toml).unwrap();
+        Report::new(&coffer, &coffer.references().unwrap())
+    }
+
+    /// Readers of the value at `location` by name
+    fn readers(report: &Report, location: &str) -> Vec<(String, &'static str)> {
+        let (_, readers) = report.access.iter().find(|(v, _)| v.location == location).unwrap();
+        readers.iter()
+            .map(|(id, access)| (report.clients.iter().find(|(i, _)| i == id).unwrap().1.name.clone(), access.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn access_follows_references_transitively() {
+        let report = report(r#"
+            [db]
+            id = "0"
+            password = "toor"
+            [backend]
+            id = "1"
+            reads = ["db"]
+            url = "postgres://app:${db.password}@db/app"
+            [frontend]
+            id = "2"
+            reads = ["backend"]
+            api = "${backend.url}"
+            [other]
+            id = "3"
+            password = "secret"
+        "#);
+
+        assert_eq!{readers(&report, "db.password"),
+                   vec![("db".to_string(), "own"), ("backend".to_string(), "reference"), ("frontend".to_string(), "reference")]};
+        assert_eq!{readers(&report, "backend.url"),
+                   vec![("backend".to_string(), "own"), ("frontend".to_string(), "reference")]};
+        assert_eq!{readers(&report, "other.password"), vec![("other".to_string(), "own")]};
+    }
+
+    #[test]
+    fn duplicates_cover_all_scalar_types() {
+        let report = report(r#"
+            [a]
+            id = "0"
+            token = "abc"
+            port = 80
+            ratio = 0.5
+            debug = true
+            empty = ""
+            text = "80"
+            [b]
+            id = "1"
+            reads = ["a"]
+            token = "abc"
+            port = 80
+            ratio = 0.5
+            debug = true
+            empty = ""
+            shared = "${a.token}"
+            [c]
+            id = "2"
+            port = "80"
+        "#);
+
+        // ordered by the values as toml
+        assert_eq!{report.duplicates, vec![
+            vec!["a.text".to_string(), "c.port".to_string()],
+            vec!["a.token".to_string(), "b.token".to_string()],
+            vec!["a.ratio".to_string(), "b.ratio".to_string()],
+            vec!["a.port".to_string(), "b.port".to_string()],
+            vec!["a.debug".to_string(), "b.debug".to_string()]
+        ]};
+    }
+
+    #[test]
+    fn duplicates_need_different_shards() {
+        let report = report("[a]\nid = \"0\"\nx = \"same\"\ny = \"same\"");
+
+        assert!{report.duplicates.is_empty()};
+    }
+}
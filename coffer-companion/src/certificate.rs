@@ -1,9 +1,14 @@
-use coffer_common::certificate::Certificate;
+use coffer_common::certificate::{fingerprint, Certificate, PublicCertificate};
+use coffer_common::coffer::Coffer;
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::signing::SigningKey;
 
-use std::path::PathBuf;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 
+use crate::add_client::shard_document;
+
 pub fn generate_key(out: PathBuf) {
     let certificate = Certificate::new().unwrap();
 
@@ -15,9 +20,171 @@ pub fn generate_key(out: PathBuf) {
     writer.write_all(&cert).unwrap();
 }
 
-pub fn info(out: PathBuf) {
-    let cert = Certificate::new_from_cbor(out).unwrap();
+/// Generate a certificate for each client in `manifest` into `out_dir`
+///
+/// The manifest lists the client names, which are also the shard names:
+/// ```toml
+///   clients = ["app.frontend", "app.backend", "database"]
+/// ```
+/// Certificates are written to `<name>.cert`. A coffer skeleton with a shard
+/// for each client is printed. Returns `false` if no certificates were written.
+pub fn generate_manifest(manifest: PathBuf, out_dir: PathBuf) -> bool {
+    let written = manifest_certificates(&manifest, &out_dir)
+        .and_then(|(skeleton, certificates)| {
+            write_certificates(&out_dir, certificates)?;
+            Ok(skeleton)
+        });
+
+    match written {
+        Ok(skeleton) => { print!{"{}", skeleton}; true }
+        Err(problem) => { eprintln!{"{}: {}", manifest.display(), problem}; false }
+    }
+}
+
+/// The coffer skeleton and the certificates to write for a manifest
+fn manifest_certificates(manifest: &Path, out_dir: &Path) -> Result<(String, Vec<(PathBuf, Certificate)>), String> {
+    let manifest: toml::Value = fs::read_to_string(manifest)
+        .map_err(|err| format!{"Could not read manifest: {}", err})?
+        .parse()
+        .map_err(|err| format!{"Invalid manifest: {}", err})?;
+
+    let names: Vec<String> = manifest.get("clients")
+        .and_then(|clients| clients.as_array())
+        .and_then(|clients| clients.iter().map(|c| c.as_str().map(str::to_string)).collect())
+        .ok_or_else(|| "clients must be a list of client names".to_string())?;
+
+    client_certificates(names, out_dir)
+}
+
+/// The coffer skeleton and a certificate in `out_dir` for each client name
+fn client_certificates(names: Vec<String>, out_dir: &Path) -> Result<(String, Vec<(PathBuf, Certificate)>), String> {
+    let mut skeleton = CofferMap::new();
+    let mut certificates = Vec::with_capacity(names.len());
+
+    for name in names {
+        let certificate = Certificate::new().unwrap();
+        let id = hex::encode_upper(certificate.public_key());
+
+        // names are shard table paths and used as file names
+        let invalid = || format!{"Invalid client name {}", name};
+        let document = shard_document(&name, &id, "").map_err(|_| invalid())?;
+        skeleton.from_toml_table("", &document)
+            .map_err(|err| format!{"{}: {}", invalid(), err})?;
+        if skeleton.get_shard_name(&id).as_deref() != Some(name.as_str()) || name.contains(&['/', '\\'][..]) {
+            return Err(invalid());
+        }
+
+        let path = out_dir.join(format!{"{}.cert", name});
+        if path.exists() {
+            return Err(format!{"Certificate {} already exists", path.display()});
+        }
+
+        certificates.push((path, certificate));
+    }
+
+    // the skeleton must be a valid coffer on its own
+    let skeleton = skeleton.to_toml();
+    CofferMap::new().put_toml(&skeleton)
+        .map_err(|err| format!{"Invalid coffer skeleton: {}", err})?;
+
+    Ok((skeleton, certificates))
+}
+
+/// Write all certificates, or none of them
+fn write_certificates(out_dir: &Path, certificates: Vec<(PathBuf, Certificate)>) -> Result<(), String> {
+    fs::create_dir_all(out_dir)
+        .map_err(|err| format!{"Could not create {}: {}", out_dir.display(), err})?;
+
+    let mut written = Vec::with_capacity(certificates.len());
+    for (path, certificate) in certificates {
+        if let Err(err) = write_certificate(&path, &certificate.to_cbor().unwrap()) {
+            for path in written {
+                let _ = fs::remove_file(path);
+            }
+            return Err(format!{"Could not write certificate {}: {}", path.display(), err});
+        }
+        written.push(path);
+    }
+
+    Ok(())
+}
+
+/// Write a new certificate file only readable by the current user
+pub fn write_certificate(out: &Path, cbor: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(out)?.write_all(cbor)
+}
+
+/// Print the public key of a certificate, and the secret key only if asked for
+pub fn info(path: PathBuf, show_secret: bool) {
+    let cert = PublicCertificate::new_from_cbor(&path).unwrap();
+    let public_key = cert.public_key();
+
+    println!{"Public Key: {}", hex::encode_upper(&public_key)}
+    println!{"Public Key (base64): {}", base64::encode(&public_key)}
+    println!{"Fingerprint: {}", fingerprint(&public_key)}
+
+    if show_secret {
+        let cert = Certificate::new_from_cbor(&path).unwrap();
+        println!{"Secret Key: {}", hex::encode_upper(cert.secret_key())}
+    }
+}
+
+/// Write the public key of a certificate to a new file
+pub fn export_public(path: PathBuf, out: PathBuf) {
+    let cert = PublicCertificate::new_from_cbor(path).unwrap();
+
+    let mut writer = OpenOptions::new().write(true).create_new(true).open(&out)
+        .unwrap_or_else(|err| panic!{"Could not create out file {}: {}", &out.display(), err});
+
+    writer.write_all(&cert.to_cbor().unwrap()).unwrap();
+}
+
+/// Generate a signing key, and optionally export its public key to `public`
+///
+/// Trusted signers of coffer-server only need the public key.
+pub fn generate_signing_key(out: PathBuf, public: Option<PathBuf>) {
+    let key = SigningKey::new().unwrap();
+
+    write_certificate(&out, &key.to_cbor().unwrap())
+        .unwrap_or_else(|err| panic!{"Could not create out file {}: {}", &out.display(), err});
+
+    if let Some(public) = public {
+        write_certificate(&public, &key.public_signing_key().to_cbor().unwrap())
+            .unwrap_or_else(|err| panic!{"Could not create out file {}: {}", &public.display(), err});
+    }
+
+    println!{"Fingerprint: {}", fingerprint(&key.public_key())}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skeleton(names: &[&str]) -> Result<String, String> {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        client_certificates(names, Path::new("/nonexistent")).map(|(skeleton, _)| skeleton)
+    }
+
+    #[test]
+    fn client_certificates_build_a_valid_skeleton() {
+        let skeleton = skeleton(&["app.frontend", "app.backend", "database"]).unwrap();
+        let mut coffer = CofferMap::new();
+        coffer.put_toml(&skeleton).unwrap();
+
+        let mut names: Vec<String> = coffer.get_shard_ids().iter().filter_map(|id| coffer.get_shard_name(id)).collect();
+        names.sort();
+        assert_eq!{names, vec!["app.backend", "app.frontend", "database"]};
+    }
 
-    println!{"Public Key: {}", hex::encode_upper(cert.public_key())}
-    println!{"Secret Key: {}", hex::encode_upper(cert.secret_key())}
+    #[test]
+    fn client_certificates_reject_invalid_names() {
+        for names in &[&["app", "app.frontend"][..], &["app.frontend", "app"], &["app", "app"], &["a..b"], &["my db"], &["../app"], &[""]] {
+            assert!{skeleton(names).is_err(), "{:?}", names};
+        }
+    }
 }
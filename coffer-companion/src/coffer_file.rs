@@ -0,0 +1,129 @@
+//! Reading and writing of plain and encrypted coffer files
+
+use coffer_common::certificate::{Certificate, PublicCertificate};
+use coffer_common::coffer::Coffer;
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::envelope::{self, Envelope};
+use coffer_common::signing::SigningKey;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Read a coffer file, decrypting it if a `certificate` is given
+pub fn read(path: &Path, certificate: Option<&Certificate>) -> Result<String, String> {
+    let content = fs::read(path)
+        .map_err(|err| format!{"Could not read file: {}", err})?;
+
+    let content = match certificate {
+        Some(certificate) => envelope::open(&content, certificate)
+            .map_err(|err| format!{"Could not decrypt with server certificate: {}", err})?,
+        None => content
+    };
+
+    String::from_utf8(content)
+        .map_err(|_| "Not valid UTF-8".to_string())
+}
+
+/// Write a coffer file, encrypting it if a `certificate` is given
+///
+/// An existing file is replaced atomically. The recipients and compression of
+/// an existing encrypted file are kept. Its signatures become invalid and are
+/// removed. Coffers that can't be read back are not written.
+pub fn write(path: &Path, toml: &str, certificate: Option<&Certificate>) -> Result<(), String> {
+    CofferMap::new().put_toml(toml)
+        .map_err(|err| format!{"Refusing to write an invalid coffer: {}", err})?;
+
+    let content = match certificate {
+        Some(certificate) => {
+            let mut recipients = recipients(path);
+            if !recipients.iter().any(|r| r.public_key() == certificate.public_key()) {
+                recipients.push(certificate.public_certificate());
+            }
+            warn_signatures(path);
+            seal(toml.as_bytes(), &recipients, compressed(path), None)?
+        }
+        None => toml.as_bytes().to_vec()
+    };
+
+    write_atomic(path, &content)
+}
+
+/// Encrypt `content` for all `recipients`, optionally compressed and signed
+pub fn seal(content: &[u8], recipients: &[PublicCertificate], compress: bool, signing_key: Option<&SigningKey>)
+            -> Result<Vec<u8>, String> {
+    Envelope::seal(content, recipients, compress)
+        .and_then(|mut envelope| {
+            if let Some(key) = signing_key { envelope.sign(key) }
+            envelope.to_bytes()
+        })
+        .map_err(|err| format!{"Could not encrypt: {}", err})
+}
+
+/// The recipients of the encrypted coffer file at `path`
+///
+/// Empty if the file does not exist or is not an envelope.
+pub fn recipients(path: &Path) -> Vec<PublicCertificate> {
+    envelope(path)
+        .map(|envelope| envelope.recipients())
+        .unwrap_or_default()
+}
+
+/// Whether the encrypted coffer file at `path` is compressed
+pub fn compressed(path: &Path) -> bool {
+    envelope(path).map_or(false, |envelope| envelope.header.compression.is_some())
+}
+
+/// Tell that the signatures of the encrypted coffer file at `path` are removed
+/// when it is written
+pub fn warn_signatures(path: &Path) {
+    if envelope(path).map_or(false, |envelope| envelope.is_signed()) {
+        eprintln!{"{}: Removing signatures of the changed coffer, sign it again", path.display()};
+    }
+}
+
+/// Tell that the plaintext coffer file at `path` is rewritten in canonical
+/// form when it is written
+pub fn warn_rewrite(path: &Path) {
+    if envelope(path).is_none() {
+        eprintln!{"{}: Rewriting the plaintext coffer in canonical form, comments are not kept", path.display()};
+    }
+}
+
+/// The envelope of the encrypted coffer file at `path`
+pub fn envelope(path: &Path) -> Option<Envelope> {
+    fs::read(path).ok()
+        .and_then(|content| Envelope::from_bytes(&content).ok())
+}
+
+/// Replace the file at `path` atomically with `content`
+///
+/// The file keeps its permissions, new files are only readable by the owner.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), String> {
+    // hidden, so coffer-server skips it when reading a directory
+    let file_name = path.file_name()
+        .ok_or_else(|| "Not a file path".to_string())?;
+    let tmp = path.with_file_name(format!{".{}.tmp", file_name.to_string_lossy()});
+
+    // left over by an interrupted write
+    let _ = fs::remove_file(&tmp);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            if let Ok(metadata) = fs::metadata(path) {
+                file.set_permissions(metadata.permissions())?;
+            }
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp);
+            format!{"Could not write file: {}", err}
+        })
+}
@@ -0,0 +1,51 @@
+//! Decryption of encrypted coffers
+
+use coffer_common::certificate::Certificate;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::coffer_file;
+
+/// Decrypt the coffer `input` with the server `certificate`
+///
+/// The plaintext is written to `out` or stdout. Existing files are never
+/// overwritten. Returns `false` if the coffer could not be decrypted or written.
+pub fn decrypt(input: PathBuf, out: Option<PathBuf>, certificate: PathBuf) -> bool {
+    let certificate = match Certificate::new_from_cbor(certificate) {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let toml = match coffer_file::read(&input, Some(&certificate)) {
+        Ok(toml) => toml,
+        Err(problem) => { eprintln!{"{}: {}", input.display(), problem}; return false }
+    };
+
+    let out = match out {
+        Some(out) => out,
+        None => {
+            if atty::is(atty::Stream::Stdout) {
+                eprintln!{"Warning: writing secrets to a terminal"};
+            }
+            print!{"{}", toml};
+            return true;
+        }
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let written = options.open(&out)
+        .and_then(|mut file| file.write_all(toml.as_bytes()));
+
+    if let Err(err) = written {
+        eprintln!{"{}: Could not write file: {}", out.display(), err};
+        return false;
+    }
+
+    true
+}
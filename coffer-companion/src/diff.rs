@@ -0,0 +1,295 @@
+//! Differences between coffers
+//!
+//! Values are masked unless explicitly shown. For reviewing encrypted coffers
+//! with `git diff`, a coffer can be printed in a redacted canonical form. See
+//! the [git module](crate::git) for setting this up.
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferKey, CofferMeta, CofferValue};
+use coffer_common::coffer_map::CofferMap;
+
+use sodiumoxide::crypto::generichash;
+use toml::Value as TomlValue;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Domain separation of the redaction key
+const REDACTION_CONTEXT: &[u8] = b"coffer-diff-redact";
+
+/// A shard with its values, ordered for comparison
+struct Shard {
+    id: String,
+    reads: Vec<String>,
+    values: BTreeMap<String, (CofferValue, CofferMeta)>
+}
+
+/// Print the differences between the coffers `old` and `new`
+///
+/// Encrypted coffers are opened with the server `certificate`. Returns `None`
+/// if a coffer could not be read, otherwise whether the coffers differ.
+pub fn diff(old: PathBuf, new: PathBuf, certificate: Option<PathBuf>, show_values: bool) -> Option<bool> {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return None }
+    };
+
+    let old = read(&old, certificate.as_ref())?;
+    let new = read(&new, certificate.as_ref())?;
+
+    let differences = differences(&shards(&old), &shards(&new), show_values);
+    for difference in &differences {
+        println!{"{}", difference};
+    }
+
+    Some(!differences.is_empty())
+}
+
+/// Print the coffer at `path` in canonical form with redacted values
+///
+/// Redacted values are replaced by a hash keyed with a key derived from the
+/// server `certificate`, so changed values still show up as changes. Without a certificate there is
+/// no key and values are only replaced by `<redacted>`, unkeyed hashes of
+/// short values can be guessed. Returns `false` if the coffer could not be
+/// read.
+pub fn textconv(path: PathBuf, certificate: Option<PathBuf>, show_values: bool) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let mut coffer = match read(&path, certificate.as_ref()) {
+        Some(coffer) => coffer,
+        None => return false
+    };
+
+    if !show_values {
+        let key = certificate.as_ref().map(redaction_key);
+        redact(&mut coffer, key.as_deref());
+    }
+
+    print!{"{}", coffer.to_toml()};
+    true
+}
+
+fn read(path: &Path, certificate: Option<&Certificate>) -> Option<CofferMap> {
+    let coffer = coffer_file::read(path, certificate).and_then(|toml| {
+        let mut coffer = CofferMap::new();
+        coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+        Ok(coffer)
+    });
+
+    coffer.map_err(|problem| eprintln!{"{}: {}", path.display(), problem}).ok()
+}
+
+/// Shards of `coffer` by name
+fn shards(coffer: &CofferMap) -> BTreeMap<String, Shard> {
+    coffer.get_shard_ids().into_iter()
+        .map(|id| {
+            let values = coffer.get_shard(&id).map(|s| s.0).unwrap_or_default().into_iter()
+                .map(|(key, value)| {
+                    let meta = coffer.get_meta(&CofferKey{shard: id.clone(), key: key.clone()})
+                        .unwrap_or_default();
+                    (key, (value, meta))
+                })
+                .collect();
+
+            let name = coffer.get_shard_name(&id).unwrap_or_else(|| id.clone());
+            (name, Shard {reads: coffer.get_shard_reads(&id), id, values})
+        })
+        .collect()
+}
+
+fn differences(old: &BTreeMap<String, Shard>, new: &BTreeMap<String, Shard>, show_values: bool) -> Vec<String> {
+    let value = |value: &CofferValue| if show_values {
+        TomlValue::from(value.clone()).to_string()
+    } else {
+        "<redacted>".to_string()
+    };
+
+    let mut differences = Vec::new();
+
+    for (name, shard) in old.iter().filter(|(name, _)| !new.contains_key(*name)) {
+        differences.push(format!{"- [{}]", name});
+        differences.extend(shard.values.keys().map(|key| format!{"- {}.{}", name, key}));
+    }
+
+    for (name, shard) in new.iter().filter(|(name, _)| !old.contains_key(*name)) {
+        differences.push(format!{"+ [{}]", name});
+        differences.extend(shard.values.iter()
+                           .map(|(key, (v, _))| format!{"+ {}.{} = {}", name, key, value(v)}));
+    }
+
+    for (name, old_shard, new_shard) in old.iter()
+        .filter_map(|(name, shard)| new.get(name).map(|new_shard| (name, shard, new_shard)))
+    {
+        if old_shard.id != new_shard.id {
+            differences.push(format!{"~ {}.id: {} -> {}", name, old_shard.id, new_shard.id});
+        }
+        if old_shard.reads != new_shard.reads {
+            differences.push(format!{"~ {}.reads: {:?} -> {:?}", name, old_shard.reads, new_shard.reads});
+        }
+
+        for key in old_shard.values.keys().filter(|key| !new_shard.values.contains_key(*key)) {
+            differences.push(format!{"- {}.{}", name, key});
+        }
+
+        for (key, (new_value, new_meta)) in &new_shard.values {
+            let (old_value, old_meta) = match old_shard.values.get(key) {
+                Some(old) => old,
+                None => {
+                    differences.push(format!{"+ {}.{} = {}", name, key, value(new_value)});
+                    continue;
+                }
+            };
+
+            if old_value != new_value {
+                let change = if show_values {
+                    format!{"{} -> {}", value(old_value), value(new_value)}
+                } else {
+                    "changed".to_string()
+                };
+                differences.push(format!{"~ {}.{}: {}", name, key, change});
+            }
+
+            differences.extend(meta_differences(old_meta, new_meta).into_iter()
+                               .map(|(field, old, new)| format!{"~ {}.{}.{}: {} -> {}", name, key, field, old, new}));
+        }
+    }
+
+    differences
+}
+
+/// Changed metadata fields with their old and new values
+fn meta_differences(old: &CofferMeta, new: &CofferMeta) -> Vec<(&'static str, String, String)> {
+    let show = |field: Option<String>| field.map_or("none".to_string(), |f| format!{"{:?}", f});
+
+    let fields = vec![
+        ("description", show(old.description.clone()), show(new.description.clone())),
+        ("owner", show(old.owner.clone()), show(new.owner.clone())),
+        ("not_after", show(old.not_after.map(|d| d.to_string())), show(new.not_after.map(|d| d.to_string())))
+    ];
+
+    fields.into_iter().filter(|(_, old, new)| old != new).collect()
+}
+
+/// The key of redaction hashes for coffers of the server `certificate`
+///
+/// Hashes are shared in diffs, so the secret key is not used directly.
+fn redaction_key(certificate: &Certificate) -> Vec<u8> {
+    let mut state = generichash::State::new(generichash::KEY_MAX, None).unwrap();
+    state.update(REDACTION_CONTEXT).unwrap();
+    state.update(&certificate.secret_key()).unwrap();
+    state.finalize().unwrap().as_ref().to_vec()
+}
+
+/// Replace all values in `coffer` by a hash keyed with `key`, or only by
+/// `<redacted>` without a key
+fn redact(coffer: &mut CofferMap, key: Option<&[u8]>) {
+    for id in coffer.get_shard_ids() {
+        for (k, value) in coffer.get_shard(&id).map(|s| s.0).unwrap_or_default() {
+            let redacted = match key {
+                Some(key) => {
+                    let mut state = generichash::State::new(generichash::DIGEST_MIN, Some(key)).unwrap();
+                    state.update(&serde_cbor::to_vec(&value).unwrap()).unwrap();
+                    format!{"<redacted {}>", hex::encode(&state.finalize().unwrap().as_ref()[..6])}
+                }
+                None => "<redacted>".to_string()
+            };
+
+            coffer.push(CofferKey{shard: id.clone(), key: k}, CofferValue::String(redacted));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"
+        [app]
+        id = "1"
+        password = "secret"
+        port = 80
+        gone = true
+
+        [old]
+        id = "2"
+        x = 1
+    "#;
+
+    const NEW: &str = r#"
+        [app]
+        id = "1"
+        reads = ["new"]
+        password = "changed"
+        port = { value = 80, owner = "ops" }
+        added = "value"
+
+        [new]
+        id = "3"
+        y = 2
+    "#;
+
+    fn differences_of(old: &str, new: &str, show_values: bool) -> Vec<String> {
+        differences(&shards(&CofferMap::from_toml(old).unwrap()), &shards(&CofferMap::from_toml(new).unwrap()), show_values)
+    }
+
+    #[test]
+    fn differences_redact_values() {
+        assert_eq!{differences_of(OLD, NEW, false), vec![
+            "- [old]",
+            "- old.x",
+            "+ [new]",
+            "+ new.y = <redacted>",
+            "~ app.reads: [] -> [\"new\"]",
+            "- app.gone",
+            "+ app.added = <redacted>",
+            "~ app.password: changed",
+            "~ app.port.owner: none -> \"ops\""
+        ]};
+    }
+
+    #[test]
+    fn differences_show_values() {
+        let differences = differences_of(OLD, NEW, true);
+
+        assert!{differences.contains(&"~ app.password: \"secret\" -> \"changed\"".to_string())};
+        assert!{differences.contains(&"+ new.y = 2".to_string())};
+    }
+
+    #[test]
+    fn differences_of_equal_coffers_are_empty() {
+        assert!{differences_of(OLD, OLD, false).is_empty()};
+    }
+
+    #[test]
+    fn redact_needs_a_key_for_hashes() {
+        let mut coffer = CofferMap::from_toml(OLD).unwrap();
+        redact(&mut coffer, None);
+        assert_eq!{coffer.get(&CofferKey{shard: "1".into(), key: "port".into()}),
+                   Some(CofferValue::String("<redacted>".into()))};
+
+        let (mut a, mut b) = (CofferMap::from_toml(OLD).unwrap(), CofferMap::from_toml(OLD).unwrap());
+        redact(&mut a, Some(&[1u8; 32]));
+        redact(&mut b, Some(&[2u8; 32]));
+        let key = CofferKey{shard: "1".into(), key: "password".into()};
+        assert_ne!{a.get(&key), b.get(&key)};
+    }
+
+    #[test]
+    fn redaction_key_is_not_the_secret_key() {
+        let certificate = Certificate::new().unwrap();
+        let key = redaction_key(&certificate);
+        assert_ne!{key, certificate.secret_key()};
+        assert_eq!{key, redaction_key(&certificate)};
+
+        let (mut a, mut b) = (CofferMap::from_toml(OLD).unwrap(), CofferMap::from_toml(OLD).unwrap());
+        redact(&mut a, Some(&key));
+        redact(&mut b, Some(&certificate.secret_key()));
+        let key = CofferKey{shard: "1".into(), key: "password".into()};
+        assert_ne!{a.get(&key), b.get(&key)};
+    }
+}
@@ -0,0 +1,166 @@
+//! Reading dotenv files
+//!
+//! Supports the common dotenv syntax:
+//! ```sh
+//!   # comment
+//!   export DB_USER=app        # comment after a value
+//!   DB_PASSWORD='lit$eral'    # no escapes in single quotes
+//!   GREETING="Hello\n\"you\"" # \n, \r, \t, \\, \" and \$ in double quotes
+//!   CERT="-----BEGIN CERTIFICATE-----
+//!   ...
+//!   -----END CERTIFICATE-----"
+//! ```
+//! Quoted values can span multiple lines. Variables are not expanded.
+
+/// Parse the `KEY=value` pairs of a dotenv file, in order
+pub fn parse(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut lines = content.lines().enumerate();
+
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let at = |problem: String| format!{"line {}: {}", number + 1, problem};
+
+        let (key, value) = line.split_at(line.find('=').ok_or_else(|| at("expected KEY=value".to_string()))?);
+        let key = key.trim_end();
+        if !is_key(key) {
+            return Err(at(format!{"invalid key \"{}\"", key}));
+        }
+
+        let value = value[1..].trim_start();
+        let value = match value.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let mut quoted = value[1..].to_string();
+                let (value, rest) = loop {
+                    if let Some(end) = closing_quote(&quoted, quote) {
+                        let rest = quoted.split_off(end);
+                        break (quoted, rest[1..].to_string());
+                    }
+                    let (_, next) = lines.next().ok_or_else(|| at(format!{"unterminated {} quote", quote}))?;
+                    quoted.push('\n');
+                    quoted.push_str(next);
+                };
+
+                let rest = rest.trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(at(format!{"unexpected \"{}\" after quoted value", rest}));
+                }
+
+                if quote == '"' { unescape(&value).map_err(at)? } else { value }
+            }
+            Some('#') => String::new(),
+            _ => {
+                // a `#` after whitespace starts a comment
+                let end = value.find(" #").or_else(|| value.find("\t#")).unwrap_or(value.len());
+                value[..end].trim_end().to_string()
+            }
+        };
+
+        pairs.push((key.to_string(), value));
+    }
+
+    Ok(pairs)
+}
+
+/// Whether `key` is a valid dotenv key
+pub fn is_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    let first = matches!{chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_'};
+    first && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Position of the closing `quote` in `value`, skipping escaped double quotes
+fn closing_quote(value: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == '"' => escaped = true,
+            c if c == quote => return Some(i),
+            _ => ()
+        }
+    }
+
+    None
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        unescaped.push(match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '$') => c,
+            Some(c) => return Err(format!{"unknown escape \\{}", c}),
+            None => return Err("trailing \\".to_string())
+        });
+    }
+
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(content: &str) -> Vec<(String, String)> {
+        parse(content).unwrap()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parse_skips_comments_and_export() {
+        let content = "# comment\n\n  export A=1 # after\nB=two\t# tab\nC=a#b\nD=#empty\n";
+
+        assert_eq!{pairs(content), vec![pair("A", "1"), pair("B", "two"), pair("C", "a#b"), pair("D", "")]};
+    }
+
+    #[test]
+    fn parse_quotes() {
+        let content = r##"
+            SINGLE='lit$eral \n "x"'  # comment
+            DOUBLE="a\n\t\"b\" \$HOME \\"
+            EMPTY=""
+            HASH="# not a comment"
+        "##;
+
+        assert_eq!{pairs(content), vec![pair("SINGLE", "lit$eral \\n \"x\""),
+                                        pair("DOUBLE", "a\n\t\"b\" $HOME \\"),
+                                        pair("EMPTY", ""),
+                                        pair("HASH", "# not a comment")]};
+    }
+
+    #[test]
+    fn parse_multiline_values() {
+        let content = "CERT=\"-----BEGIN-----\nabc\n-----END-----\"\nNEXT='a\nb'\n";
+
+        assert_eq!{pairs(content), vec![pair("CERT", "-----BEGIN-----\nabc\n-----END-----"),
+                                        pair("NEXT", "a\nb")]};
+    }
+
+    #[test]
+    fn parse_reports_errors() {
+        assert_eq!{parse("A=1\nno value").unwrap_err(), "line 2: expected KEY=value"};
+        assert_eq!{parse("1A=1").unwrap_err(), "line 1: invalid key \"1A\""};
+        assert_eq!{parse("A=\"open\nB=1").unwrap_err(), "line 1: unterminated \" quote"};
+        assert_eq!{parse("A='x' y").unwrap_err(), "line 1: unexpected \"y\" after quoted value"};
+        assert_eq!{parse("A=\"\\q\"").unwrap_err(), "line 1: unknown escape \\q"};
+    }
+}
@@ -0,0 +1,243 @@
+//! Editing of encrypted coffers in place
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::Coffer;
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::keyring::decode_public_key;
+
+use sodiumoxide::randombytes::randombytes;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::coffer_file;
+
+/// A private temporary file, wiped when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Create a file only readable by the current user, in memory if possible
+    fn new(content: &str) -> io::Result<TempFile> {
+        let shm = Path::new("/dev/shm");
+        let dir = if shm.is_dir() { shm.to_path_buf() } else { std::env::temp_dir() };
+        let path = dir.join(format!{"coffer-edit-{}.toml", hex::encode(randombytes(8))});
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&path)?;
+        let temp = TempFile(path);
+        file.write_all(content.as_bytes())?;
+
+        Ok(temp)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // overwrite the plaintext before unlinking
+        if let Ok(mut file) = OpenOptions::new().write(true).open(&self.0) {
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            let _ = file.write_all(&vec![0u8; len as usize]);
+            let _ = file.sync_all();
+        }
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Set when a deferred signal arrived
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupted(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Defers interrupts and termination while alive, so the temporary file is
+/// still wiped. The editor gets the default handlers again when it starts.
+#[cfg(unix)]
+struct DeferSignals(Vec<(libc::c_int, libc::sighandler_t)>);
+
+#[cfg(unix)]
+impl DeferSignals {
+    fn new() -> DeferSignals {
+        let signals = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGHUP];
+
+        DeferSignals(signals.iter()
+            .map(|&signal| (signal, unsafe { libc::signal(signal, interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t) }))
+            .collect())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for DeferSignals {
+    fn drop(&mut self) {
+        for &(signal, handler) in &self.0 {
+            unsafe { libc::signal(signal, handler); }
+        }
+    }
+}
+
+/// Edit the encrypted coffer at `path` with `$EDITOR`
+///
+/// The coffer is decrypted into a private temporary file and sealed again with
+/// the server `certificate` after editing. Edits are checked like coffer-server
+/// reads coffers, invalid edits can be fixed in the editor. Returns `false` if
+/// the coffer was not changed because of an error.
+pub fn edit(path: PathBuf, certificate: PathBuf) -> bool {
+    let cert = match Certificate::new_from_cbor(&certificate) {
+        Ok(cert) => cert,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let original = match coffer_file::read(&path, Some(&cert)) {
+        Ok(toml) => toml,
+        Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; return false }
+    };
+
+    // dropped after the temporary file
+    #[cfg(unix)]
+    let _signals = DeferSignals::new();
+
+    let temp = match TempFile::new(&original) {
+        Ok(temp) => temp,
+        Err(err) => { eprintln!{"Could not create temporary file: {}", err}; return false }
+    };
+
+    let edited = loop {
+        if let Err(problem) = run_editor(&temp.0) {
+            eprintln!{"{}", problem};
+            return false;
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            eprintln!{"{}: Interrupted, edits discarded", path.display()};
+            return false;
+        }
+
+        let edited = match fs::read_to_string(&temp.0) {
+            Ok(edited) => edited,
+            Err(err) => { eprintln!{"Could not read temporary file: {}", err}; return false }
+        };
+
+        if edited == original {
+            println!{"{}: No changes", path.display()};
+            return true;
+        }
+
+        match check(&edited) {
+            Ok(()) => break edited,
+            Err(problem) => eprintln!{"{}", problem}
+        }
+
+        if !ask("Edit again? [Y/n] ") || INTERRUPTED.load(Ordering::SeqCst) {
+            eprintln!{"{}: Edits discarded", path.display()};
+            return false;
+        }
+    };
+
+    if let Err(problem) = coffer_file::write(&path, &edited, Some(&cert)) {
+        eprintln!{"{}: {}", path.display(), problem};
+        return false;
+    }
+
+    true
+}
+
+/// Open `path` in the user's editor
+fn run_editor(path: &Path) -> Result<(), String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    // editors can be given with arguments, e.g. `code --wait`
+    let mut args = editor.split_whitespace();
+    let program = args.next().ok_or_else(|| "No editor set".to_string())?;
+
+    let status = Command::new(program).args(args).arg(path).status()
+        .map_err(|err| format!{"Could not start editor {}: {}", program, err})?;
+
+    if status.success() { Ok(()) } else { Err(format!{"Editor {} failed", program}) }
+}
+
+/// Check an edited coffer like coffer-server does when reading it
+///
+/// Shard ids must be public keys of clients.
+fn check(toml: &str) -> Result<(), String> {
+    let mut coffer = CofferMap::new();
+    coffer.put_toml(toml).map_err(|err| err.to_string())?;
+    coffer.resolve_references().map_err(|err| err.to_string())?;
+
+    let mut ids = coffer.get_shard_ids();
+    ids.sort();
+    for id in ids {
+        if let Err(err) = decode_public_key(&id) {
+            return Err(format!{"Invalid shard id {}: {:?}", id, err});
+        }
+    }
+
+    Ok(())
+}
+
+/// Ask a yes/no question, defaulting to yes
+fn ask(question: &str) -> bool {
+    eprint!{"{}", question};
+
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => !answer.trim().eq_ignore_ascii_case("n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "2A94D9C286389485E8A5FE6AD174E89AA0E0E50CAE0AF09054CE5BE06EC4C00B";
+
+    #[test]
+    fn check_accepts_valid_coffers() {
+        assert_eq!{check(&format!{"[app]\nid = \"{}\"\na = \"${{b}}\"\nb = 1", ID}), Ok(())};
+    }
+
+    #[test]
+    fn check_rejects_invalid_documents() {
+        assert!{check("[app").unwrap_err().starts_with("Invalid toml: ")};
+        assert_eq!{check(&format!{"[app]\nid = \"{}\"\nreads = 1", ID}).unwrap_err(),
+                   "Invalid definition of app.reads: reads is reserved for the shards this shard references, must be a list of shard names"};
+    }
+
+    #[test]
+    fn check_rejects_unresolved_references() {
+        assert_eq!{check(&format!{"[app]\nid = \"{}\"\na = \"${{b}}\"", ID}).unwrap_err(),
+                   "Invalid reference in app.a: unknown value ${b}"};
+    }
+
+    #[test]
+    fn check_rejects_invalid_shard_ids() {
+        assert!{check("[app]\nid = \"1\"").unwrap_err().starts_with("Invalid shard id 1: ")};
+        assert!{check("[app]\nid = \"AAAA\"").unwrap_err().starts_with("Invalid shard id AAAA: ")};
+    }
+
+    #[test]
+    fn temp_file_is_private_and_wiped() {
+        let temp = TempFile::new("secret").unwrap();
+        assert_eq!{fs::read_to_string(&temp.0).unwrap(), "secret"};
+        #[cfg(unix)]
+        assert_eq!{std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&temp.0).unwrap().permissions()) & 0o777, 0o600};
+
+        // a second link keeps the content readable after removal
+        let link = temp.0.with_extension("link");
+        fs::hard_link(&temp.0, &link).unwrap();
+        let path = temp.0.clone();
+        drop(temp);
+
+        assert!{!path.exists()};
+        assert_eq!{fs::read(&link).unwrap(), vec![0u8; 6]};
+        fs::remove_file(link).unwrap();
+    }
+}
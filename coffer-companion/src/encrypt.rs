@@ -1,17 +1,45 @@
-use coffer_common::certificate::Certificate;
+use coffer_common::certificate::PublicCertificate;
+use coffer_common::coffer::CofferError;
+use coffer_common::signing::SigningKey;
 
 use std::path::PathBuf;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 
+use crate::coffer_file;
+use crate::materialize::materialize_toml;
+
+/// Seal the coffer definition `yaml` for the server `certificates`
+///
+/// Any of the servers can open the sealed coffer. Certificates can be full or
+/// exported public certificates.
+///
+/// With `compress`, the coffer is compressed before encryption. With a
+/// `signing_key`, the sealed coffer is signed.
+///
+/// Generated values are materialized in the sealed coffer only. Encrypting the
+/// definition again generates new values, use `materialize` to keep them.
 #[allow(unused)]
-pub fn encrypt_yaml(yaml:PathBuf, out: PathBuf, certificate: PathBuf) {
-    let cert = Certificate::new_from_cbor(certificate).unwrap();
+pub fn encrypt_yaml(yaml:PathBuf, out: PathBuf, certificates: Vec<PathBuf>, compress: bool, signing_key: Option<PathBuf>) {
+    let recipients: Vec<PublicCertificate> = certificates.iter()
+        .map(|c| PublicCertificate::new_from_cbor(c).unwrap())
+        .collect();
+    let signing_key = signing_key.map(|k| SigningKey::new_from_cbor(k).unwrap());
     let mut secrets = Vec::new();
     File::open(yaml).unwrap().read_to_end(&mut secrets).unwrap();
 
-    let sealed = cert.seal(&secrets).unwrap();
+    let materialized = match std::str::from_utf8(&secrets).map(materialize_toml) {
+        Ok(Ok(materialized)) => materialized,
+        Ok(Err(CofferError::Toml(_))) | Err(_) => None, // sealed as is
+        Ok(Err(err)) => panic!{"Could not generate values: {}", err}
+    };
+    if let Some((toml, count)) = materialized {
+        println!{"Generated {} values", count};
+        secrets = toml.into_bytes();
+    }
+
+    let sealed = coffer_file::seal(&secrets, &recipients, compress, signing_key.as_ref()).unwrap();
     let mut out_file = File::create(out).unwrap();
     out_file.write_all(&sealed);
 }
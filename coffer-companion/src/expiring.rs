@@ -0,0 +1,58 @@
+//! Listing of expiring coffer values
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferKey};
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::date::Date;
+
+use std::path::PathBuf;
+
+use crate::coffer_file;
+
+/// List values in the coffer definitions in `paths` expiring within `days`
+///
+/// Already expired values are listed too. Encrypted coffers are opened with the
+/// server `certificate`. Returns `false` if a coffer could not be read.
+pub fn expiring(paths: Vec<PathBuf>, days: i64, certificate: Option<PathBuf>) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+    let today = Date::today();
+    let mut ok = true;
+
+    for path in &paths {
+        let coffer = coffer_file::read(path, certificate.as_ref()).and_then(|toml| {
+            let mut coffer = CofferMap::new();
+            coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+            Ok(coffer)
+        });
+        let coffer = match coffer {
+            Ok(coffer) => coffer,
+            Err(problem) => { println!{"{}: {}", path.display(), problem}; ok = false; continue }
+        };
+
+        let mut expiring = Vec::new();
+        for id in coffer.get_shard_ids() {
+            let name = coffer.get_shard_name(&id).unwrap_or_else(|| id.clone());
+
+            for (key, _) in coffer.get_shard(&id).map(|s| s.0).unwrap_or_default() {
+                let meta = coffer.get_meta(&CofferKey{shard: id.clone(), key: key.clone()});
+                if let Some((not_after, owner)) = meta.and_then(|m| m.not_after.map(|n| (n, m.owner))) {
+                    if not_after <= today.add_days(days) {
+                        expiring.push((not_after, format!{"{}.{}", name, key}, owner));
+                    }
+                }
+            }
+        }
+        expiring.sort();
+
+        for (not_after, location, owner) in expiring {
+            let state = if not_after < today { "expired" } else { "expires" };
+            let owner = owner.map(|o| format!{" (owner {})", o}).unwrap_or_default();
+            println!{"{}: {} {} {}{}", path.display(), location, state, not_after, owner};
+        }
+    }
+
+    ok
+}
@@ -0,0 +1,193 @@
+//! Exporting shards of encrypted coffers to other formats
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferValue};
+use coffer_common::coffer_map::CofferMap;
+
+use serde_json::Value as JsonValue;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::{coffer_file, dotenv};
+use crate::secret::shard_id;
+
+/// Print `shard` of the encrypted coffer at `path` in `format`
+///
+/// References are resolved, values are exported like clients receive them.
+/// Formats are
+/// - `dotenv`: `KEY='value'`, double quoted with escapes if needed. Readable
+///   by `import-dotenv`. Keys that are not valid dotenv keys are skipped.
+/// - `json`: an object of the values with their types. Floats that are not
+///   finite have no json representation and are an error.
+/// - `shell`: `export KEY='value'` for POSIX shells. Keys that are not valid
+///   shell variable names are skipped.
+///
+/// Returns `false` if the shard could not be exported.
+pub fn export(path: PathBuf, certificate: PathBuf, shard: String, format: String) -> bool {
+    let certificate = match Certificate::new_from_cbor(certificate) {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let values = coffer_file::read(&path, Some(&certificate)).and_then(|toml| {
+        let mut coffer = CofferMap::new();
+        coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+        coffer.resolve_references().map_err(|err| err.to_string())?;
+
+        let id = shard_id(&coffer, &shard)?;
+        Ok(coffer.get_shard(&id).map(|s| s.0).unwrap_or_default().into_iter().collect::<BTreeMap<_, _>>())
+    });
+
+    let values = match values {
+        Ok(values) => values,
+        Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; return false }
+    };
+
+    if atty::is(atty::Stream::Stdout) {
+        eprintln!{"Warning: writing secrets to a terminal"};
+    }
+
+    match format.as_str() {
+        "dotenv" => for (key, value) in values {
+            if !dotenv::is_key(&key) {
+                eprintln!{"Skipping {}, not a valid dotenv key", key};
+                continue;
+            }
+            println!{"{}={}", key, dotenv_quote(&value.to_string())};
+        },
+        "json" => {
+            let object = values.into_iter()
+                .map(|(key, value)| json_value(value)
+                     .map(|value| (key.clone(), value))
+                     .map_err(|problem| format!{"{}: {}", key, problem}))
+                .collect::<Result<serde_json::Map<_, _>, _>>();
+
+            match object {
+                Ok(object) => println!{"{}", serde_json::to_string_pretty(&object).unwrap()},
+                Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; return false }
+            }
+        }
+        "shell" => for (key, value) in values {
+            if !is_shell_name(&key) {
+                eprintln!{"Skipping {}, not a valid shell variable name", key};
+                continue;
+            }
+            println!{"export {}={}", key, shell_quote(&value.to_string())};
+        },
+        format => unreachable!{"unknown format {}", format}
+    }
+
+    true
+}
+
+/// Single quote `value` if possible, single quoted values are never
+/// interpreted
+fn dotenv_quote(value: &str) -> String {
+    if !value.contains(&['\'', '\n', '\r'][..]) {
+        return format!{"'{}'", value};
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' | '"' | '$' => { quoted.push('\\'); quoted.push(c) }
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Single quote `value`, ending the quotes around each `'`
+fn shell_quote(value: &str) -> String {
+    format!{"'{}'", value.replace('\'', "'\\''")}
+}
+
+fn is_shell_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    let first = matches!{chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_'};
+    first && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn json_value(value: CofferValue) -> Result<JsonValue, String> {
+    match value {
+        CofferValue::String(s) => Ok(JsonValue::String(s)),
+        CofferValue::Integer(i) => Ok(JsonValue::from(i)),
+        CofferValue::Float(f) if !f.is_finite() => Err(format!{"{} has no json representation", f}),
+        // through the shortest representation of the f32, like in toml
+        CofferValue::Float(f) => Ok(JsonValue::from(f.to_string().parse::<f64>().unwrap())),
+        CofferValue::Boolean(b) => Ok(JsonValue::Bool(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values that must not be interpreted by a shell or dotenv parser
+    const HOSTILE: [&str; 8] = [
+        "plain",
+        "it's",
+        "$HOME ${HOME}",
+        "`id` $(id)",
+        "a\nb\r\n",
+        "back\\slash\\",
+        "\"double\" and 'single'",
+        "'; rm -rf / #"
+    ];
+
+    #[test]
+    fn dotenv_quote_roundtrips() {
+        for value in &HOSTILE {
+            let line = format!{"KEY={}", dotenv_quote(value)};
+            assert_eq!{dotenv::parse(&line).unwrap(), vec![("KEY".to_string(), value.to_string())], "{}", line};
+        }
+    }
+
+    #[test]
+    fn dotenv_quote_escapes_in_double_quotes() {
+        assert_eq!{dotenv_quote("$x `y`"), "'$x `y`'"};
+        assert_eq!{dotenv_quote("it's $x \\"), "\"it's \\$x \\\\\""};
+        assert_eq!{dotenv_quote("a\nb"), "\"a\\nb\""};
+    }
+
+    #[test]
+    fn shell_quote_is_literal() {
+        assert_eq!{shell_quote("$HOME `id` \\"), "'$HOME `id` \\'"};
+        assert_eq!{shell_quote("it's"), "'it'\\''s'"};
+        assert_eq!{shell_quote("a\nb"), "'a\nb'"};
+    }
+
+    #[test]
+    fn shell_quote_roundtrips_through_sh() {
+        for value in &HOSTILE {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!{"printf %s {}", shell_quote(value)})
+                .output()
+                .unwrap();
+            assert_eq!{String::from_utf8(output.stdout).unwrap(), *value};
+        }
+    }
+
+    #[test]
+    fn is_shell_name_rejects_invalid_names() {
+        assert!{is_shell_name("_DB_PASSWORD1")};
+        for name in &["", "1A", "A.B", "A-B", "A B", "A=B", "$(id)"] {
+            assert!{!is_shell_name(name), "{}", name};
+        }
+    }
+
+    #[test]
+    fn json_value_rejects_non_finite_floats() {
+        assert!{json_value(CofferValue::Float(f32::NAN)).is_err()};
+        assert!{json_value(CofferValue::Float(f32::INFINITY)).is_err()};
+        assert_eq!{json_value(CofferValue::Float(1.4)).unwrap(), serde_json::json!{1.4}};
+    }
+}
@@ -0,0 +1,293 @@
+//! git integration for encrypted coffers
+//!
+//! With the server certificate in `COFFER_SERVER_CERTIFICATE`, git can show
+//! redacted diffs of encrypted coffers and merge them:
+//! ```sh
+//!   git config diff.coffer.textconv "coffer-companion git-textconv"
+//!   git config merge.coffer.driver "coffer-companion git-merge %O %A %B %P"
+//!   echo '*.enc diff=coffer merge=coffer' >> .gitattributes
+//! ```
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferKey, CofferMeta, CofferValue};
+use coffer_common::coffer_map::CofferMap;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// A single mergeable part of a shard
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Field {
+    Id,
+    Reads,
+    Value(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    Id(String),
+    Reads(Vec<String>),
+    Value(CofferValue, CofferMeta)
+}
+
+type Items = BTreeMap<(String, Field), Item>;
+
+/// Three-way merge the coffers `base`, `ours` and `theirs` into `ours`
+///
+/// Shards are merged by name, their values by key. Changes to the same value on
+/// both sides are conflicts; ours is kept for them, since encrypted coffers
+/// can't hold conflict markers. If shards end up with the same id, ours is not
+/// changed at all. Encrypted coffers are opened and sealed again with the
+/// server `certificate`. Returns `false` if there are conflicts, so git marks
+/// the coffer as conflicted, or if a coffer could not be read.
+pub fn merge(base: PathBuf, ours: PathBuf, theirs: PathBuf, name: Option<String>, certificate: Option<PathBuf>) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+    let name = name.unwrap_or_else(|| ours.display().to_string());
+
+    let read = |path: &Path| coffer_file::read(path, certificate.as_ref()).and_then(|toml| {
+        let mut coffer = CofferMap::new();
+        coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+        Ok(items(&coffer))
+    });
+
+    let (base, our_items, their_items) = match (read(&base), read(&ours), read(&theirs)) {
+        (Ok(base), Ok(ours), Ok(theirs)) => (base, ours, theirs),
+        (Err(problem), _, _) | (_, Err(problem), _) | (_, _, Err(problem)) => {
+            eprintln!{"{}: {}", name, problem};
+            return false;
+        }
+    };
+
+    let (merged, conflicts) = merge_items(&base, &our_items, &their_items);
+    for conflict in &conflicts {
+        eprintln!{"CONFLICT ({}): {}", name, conflict};
+    }
+
+    // the merged shards can't be written, leave ours as is
+    let duplicates = duplicate_ids(&merged);
+    for duplicate in &duplicates {
+        eprintln!{"CONFLICT ({}): {}", name, duplicate};
+    }
+    if !duplicates.is_empty() {
+        return false;
+    }
+
+    if let Err(problem) = coffer_file::write(&ours, &coffer(merged).to_toml(), certificate.as_ref()) {
+        eprintln!{"{}: {}", name, problem};
+        return false;
+    }
+
+    if !conflicts.is_empty() {
+        eprintln!{"{}: {} conflicts, kept our side of them. Resolve them with edit and mark the coffer as resolved",
+                  name, conflicts.len()};
+        return false;
+    }
+
+    true
+}
+
+/// The mergeable parts of all shards in `coffer`, by shard name
+fn items(coffer: &CofferMap) -> Items {
+    let mut items = Items::new();
+
+    for id in coffer.get_shard_ids() {
+        let name = coffer.get_shard_name(&id).unwrap_or_else(|| id.clone());
+
+        for (key, value) in coffer.get_shard(&id).map(|s| s.0).unwrap_or_default() {
+            let meta = coffer.get_meta(&CofferKey{shard: id.clone(), key: key.clone()})
+                .unwrap_or_default();
+            items.insert((name.clone(), Field::Value(key)), Item::Value(value, meta));
+        }
+
+        let reads = coffer.get_shard_reads(&id);
+        if !reads.is_empty() {
+            items.insert((name.clone(), Field::Reads), Item::Reads(reads));
+        }
+        items.insert((name, Field::Id), Item::Id(id));
+    }
+
+    items
+}
+
+fn merge_items(base: &Items, ours: &Items, theirs: &Items) -> (Items, Vec<String>) {
+    let fields: BTreeSet<&(String, Field)> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+
+    let mut merged = Items::new();
+    let mut conflicts = Vec::new();
+
+    for field in fields {
+        let (b, o, t) = (base.get(field), ours.get(field), theirs.get(field));
+
+        let item = if o == t || b == t {
+            o
+        } else if b == o {
+            t
+        } else {
+            let (name, field) = field;
+            conflicts.push(match field {
+                Field::Id => format!{"{}.id changed on both sides", name},
+                Field::Reads => format!{"{}.reads changed on both sides", name},
+                Field::Value(key) => format!{"{}.{} changed on both sides", name, key}
+            });
+            o
+        };
+
+        if let Some(item) = item {
+            merged.insert(field.clone(), item.clone());
+        }
+    }
+
+    // a shard removed on one side but changed on the other lost its id, keep
+    // our version of it
+    let removed: BTreeSet<String> = merged.keys()
+        .map(|(name, _)| name.clone())
+        .filter(|name| !merged.contains_key(&(name.clone(), Field::Id)))
+        .collect();
+    for name in removed {
+        conflicts.push(format!{"{} removed on one side but changed on the other", name});
+
+        let theirs: Vec<(String, Field)> = merged.keys().filter(|(n, _)| *n == name).cloned().collect();
+        for field in theirs {
+            merged.remove(&field);
+        }
+        merged.extend(ours.iter()
+                      .filter(|((n, _), _)| *n == name)
+                      .map(|(field, item)| (field.clone(), item.clone())));
+    }
+
+    (merged, conflicts)
+}
+
+/// Shards with the same id, e.g. after renaming a shard differently on both
+/// sides
+fn duplicate_ids(items: &Items) -> Vec<String> {
+    let mut ids: BTreeMap<&str, &str> = BTreeMap::new();
+    let mut duplicates = Vec::new();
+
+    for ((name, _), item) in items {
+        if let Item::Id(id) = item {
+            if let Some(other) = ids.insert(id, name) {
+                duplicates.push(format!{"{} and {} have the same id", other, name});
+            }
+        }
+    }
+
+    duplicates
+}
+
+/// Build a coffer from merged items
+fn coffer(items: Items) -> CofferMap {
+    let mut coffer = CofferMap::new();
+
+    let ids: BTreeMap<String, String> = items.iter()
+        .filter_map(|((name, _), item)| match item {
+            Item::Id(id) => Some((name.clone(), id.clone())),
+            _ => None
+        })
+        .collect();
+
+    for ((name, field), item) in items {
+        let shard = ids[&name].clone();
+
+        match (field, item) {
+            (_, Item::Id(_)) => coffer.put_shard_name(shard, name),
+            (_, Item::Reads(reads)) => coffer.put_shard_reads(shard, reads),
+            (Field::Value(key), Item::Value(value, meta)) => {
+                let key = CofferKey{shard, key};
+                coffer.push(key.clone(), value);
+                if meta != CofferMeta::default() {
+                    coffer.put_meta(key, meta);
+                }
+            }
+            (field, item) => unreachable!{"{:?} for {:?}", item, field}
+        }
+    }
+
+    coffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Items {
+        items(&CofferMap::from_toml(toml).unwrap())
+    }
+
+    fn value(items: &Items, shard: &str, key: &str) -> Option<CofferValue> {
+        match items.get(&(shard.to_string(), Field::Value(key.to_string()))) {
+            Some(Item::Value(value, _)) => Some(value.clone()),
+            _ => None
+        }
+    }
+
+    const BASE: &str = r#"
+        [app]
+        id = "1"
+        a = "base"
+        b = "base"
+        c = "base"
+    "#;
+
+    #[test]
+    fn merge_items_takes_changes_of_either_side() {
+        let ours = parse(&BASE.replace(r#"a = "base""#, r#"a = "ours""#));
+        let theirs = parse(&BASE.replace(r#"b = "base""#, r#"b = "theirs""#));
+
+        let (merged, conflicts) = merge_items(&parse(BASE), &ours, &theirs);
+
+        assert!{conflicts.is_empty()};
+        assert_eq!{value(&merged, "app", "a"), Some(CofferValue::String("ours".into()))};
+        assert_eq!{value(&merged, "app", "b"), Some(CofferValue::String("theirs".into()))};
+        assert_eq!{value(&merged, "app", "c"), Some(CofferValue::String("base".into()))};
+    }
+
+    #[test]
+    fn merge_items_conflicts_on_both_sides_changed() {
+        let ours = parse(&BASE.replace(r#"a = "base""#, r#"a = "ours""#));
+        let theirs = parse(&BASE.replace(r#"a = "base""#, r#"a = "theirs""#));
+        let same = parse(&BASE.replace(r#"a = "base""#, r#"a = "ours""#));
+
+        let (merged, conflicts) = merge_items(&parse(BASE), &ours, &theirs);
+        assert_eq!{conflicts, vec!["app.a changed on both sides".to_string()]};
+        assert_eq!{value(&merged, "app", "a"), Some(CofferValue::String("ours".into()))};
+
+        // the same change on both sides is no conflict
+        let (_, conflicts) = merge_items(&parse(BASE), &ours, &same);
+        assert!{conflicts.is_empty()};
+    }
+
+    #[test]
+    fn merge_items_conflicts_on_removed_and_changed() {
+        let changed = parse(&BASE.replace(r#"a = "base""#, r#"a = "theirs""#));
+
+        // a value
+        let ours = parse(&BASE.replace(r#"a = "base""#, ""));
+        let (merged, conflicts) = merge_items(&parse(BASE), &ours, &changed);
+        assert_eq!{conflicts, vec!["app.a changed on both sides".to_string()]};
+        assert_eq!{value(&merged, "app", "a"), None};
+
+        // a whole shard
+        let (merged, conflicts) = merge_items(&parse(BASE), &changed, &Items::new());
+        assert_eq!{conflicts.last().unwrap(), "app removed on one side but changed on the other"};
+        assert_eq!{value(&merged, "app", "a"), Some(CofferValue::String("theirs".into()))};
+        assert_eq!{merged.get(&("app".to_string(), Field::Id)), Some(&Item::Id("1".into()))};
+    }
+
+    #[test]
+    fn merge_items_finds_duplicate_ids() {
+        // renamed differently on both sides
+        let ours = parse(&BASE.replace("[app]", "[frontend]"));
+        let theirs = parse(&BASE.replace("[app]", "[web]"));
+
+        let (merged, conflicts) = merge_items(&parse(BASE), &ours, &theirs);
+
+        assert!{conflicts.is_empty()};
+        assert_eq!{duplicate_ids(&merged), vec!["frontend and web have the same id".to_string()]};
+    }
+}
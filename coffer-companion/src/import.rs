@@ -0,0 +1,83 @@
+//! Importing values from other formats into coffers
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferKey, CofferValue, RESERVED_KEYS};
+use coffer_common::coffer_map::CofferMap;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{coffer_file, dotenv};
+use crate::secret::shard_id;
+
+/// Import the values of the dotenv file `env` into `shard` of the coffer at
+/// `path`
+///
+/// Encrypted coffers are opened with the server `certificate`. Values are
+/// imported literally, `${` does not start a reference. Keys that already
+/// exist with a different value are conflicts and reported. They are only
+/// replaced with `overwrite`, otherwise nothing is imported. Files with the
+/// reserved keys `id` or `reads` are not imported. Plaintext coffers are
+/// rewritten in canonical form, without comments. Returns `false` if nothing
+/// was imported.
+pub fn import_dotenv(path: PathBuf, certificate: Option<PathBuf>, shard: String, env: PathBuf, overwrite: bool) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    match import(&path, certificate.as_ref(), &shard, &env, overwrite) {
+        Ok(()) => true,
+        Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; false }
+    }
+}
+
+fn import(path: &Path, certificate: Option<&Certificate>, shard: &str, env: &Path, overwrite: bool) -> Result<(), String> {
+    let pairs = fs::read_to_string(env)
+        .map_err(|err| format!{"Could not read {}: {}", env.display(), err})
+        .and_then(|content| dotenv::parse(&content).map_err(|err| format!{"{}: {}", env.display(), err}))?;
+
+    if let Some((key, _)) = pairs.iter().find(|(key, _)| RESERVED_KEYS.contains(&key.as_str())) {
+        return Err(format!{"{}: {} is reserved and not a value, nothing imported", env.display(), key});
+    }
+
+    let mut coffer = CofferMap::new();
+    coffer.put_toml(&coffer_file::read(path, certificate)?)
+        .map_err(|err| err.to_string())?;
+    let id = shard_id(&coffer, shard)?;
+
+    let (mut added, mut unchanged, mut conflicts) = (0, 0, Vec::new());
+
+    for (key, value) in pairs {
+        let key = CofferKey{shard: id.clone(), key};
+        let value = CofferValue::String(value.replace("${", "$${"));
+
+        match coffer.get(&key) {
+            Some(existing) if existing == value => { unchanged += 1; continue }
+            Some(_) => conflicts.push(key.key.clone()),
+            None => added += 1
+        }
+
+        coffer.push(key, value);
+    }
+
+    for conflict in &conflicts {
+        eprintln!{"CONFLICT: {}.{} has a different value{}", shard, conflict,
+                  if overwrite { ", overwriting" } else { "" }};
+    }
+    if !conflicts.is_empty() && !overwrite {
+        return Err(format!{"{} conflicting keys, nothing imported", conflicts.len()});
+    }
+
+    if added == 0 && conflicts.is_empty() {
+        println!{"No changes, all {} values already in {}", unchanged, shard};
+        return Ok(());
+    }
+
+    coffer_file::warn_rewrite(path);
+    coffer_file::write(path, &coffer.to_toml(), certificate)?;
+
+    println!{"Imported {} new, {} overwritten and {} unchanged values into {}",
+             added, conflicts.len(), unchanged, shard};
+    Ok(())
+}
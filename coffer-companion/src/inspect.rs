@@ -0,0 +1,46 @@
+//! Inspection of encrypted coffer files without decrypting them
+
+use coffer_common::certificate::fingerprint;
+use coffer_common::envelope::Envelope;
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Print the envelope header of the coffer files in `paths`
+///
+/// Returns `false` if any file could not be read or has an invalid header.
+pub fn inspect(paths: Vec<PathBuf>) -> bool {
+    let mut ok = true;
+
+    for path in &paths {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) => { eprintln!{"{}: Could not read file: {}", path.display(), err}; ok = false; continue }
+        };
+
+        if !Envelope::is_envelope(&content) {
+            println!{"{}: sealed for a single certificate, without envelope header", path.display()};
+            continue;
+        }
+
+        match Envelope::from_bytes(&content) {
+            Ok(envelope) => {
+                println!{"{}:", path.display()};
+                println!{"  version: {}", envelope.version};
+                println!{"  payload: {}", envelope.header.payload};
+                println!{"  compression: {}", envelope.header.compression.as_deref().unwrap_or("none")};
+                println!{"  recipients:"};
+                for fingerprint in envelope.header.fingerprints() {
+                    println!{"    {}", fingerprint};
+                }
+                println!{"  signed by:"};
+                for (public_key, valid) in envelope.signatures() {
+                    println!{"    {}{}", fingerprint(&public_key), if valid { "" } else { " (invalid signature)" }};
+                }
+            }
+            Err(err) => { eprintln!{"{}: {}", path.display(), err}; ok = false }
+        }
+    }
+
+    ok
+}
@@ -1,26 +1,307 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+mod add_client;
+mod audit;
 mod certificate;
+mod coffer_file;
+mod decrypt;
+mod diff;
+mod dotenv;
+mod edit;
 mod encrypt;
+mod export;
+mod git;
+mod expiring;
+mod import;
+mod inspect;
+mod materialize;
+mod rekey;
+mod secret;
+mod sign;
+mod status;
+mod validate;
 
 #[derive(StructOpt, Debug)]
 enum Args {
+    /// Generate a certificate, or one per client in a manifest
     Certificate {
-        #[structopt(parse(from_os_str))]
-        path: PathBuf,
+        #[structopt(parse(from_os_str), required_unless = "manifest")]
+        path: Option<PathBuf>,
+        /// Manifest listing client names, prints a coffer skeleton for them
+        #[structopt(long, parse(from_os_str), conflicts_with = "path", requires = "out-dir")]
+        manifest: Option<PathBuf>,
+        /// Directory for the client certificates of a manifest
+        #[structopt(long, parse(from_os_str))]
+        out_dir: Option<PathBuf>
+    },
+    /// Create a client certificate and a shard for it in an encrypted coffer
+    AddClient {
+        /// Name of the client shard
+        name: String,
+        #[structopt(long, parse(from_os_str))]
+        coffer: PathBuf,
+        #[structopt(long, parse(from_os_str))]
+        server_cert: PathBuf,
+        /// Path of the new client certificate
+        #[structopt(short, long, parse(from_os_str))]
+        out: PathBuf,
+        /// File with the initial values of the shard
+        #[structopt(short, long, parse(from_os_str))]
+        template: Option<PathBuf>
     },
     Encrypt {
+        /// Server certificate or its exported public key, can be given
+        /// multiple times to encrypt for multiple servers
+        #[structopt(short, long, parse(from_os_str), required = true, number_of_values = 1)]
+        certificate: Vec<PathBuf>,
+        #[structopt(short, long, parse(from_os_str))]
+        yaml: PathBuf,
+        #[structopt(short, long, parse(from_os_str))]
+        out: PathBuf,
+        /// Compress the coffer before encrypting
+        #[structopt(long)]
+        compress: bool,
+        /// Sign the encrypted coffer
+        #[structopt(short = "k", long, parse(from_os_str))]
+        signing_key: Option<PathBuf>
+    },
+    /// Decrypt a coffer to stdout or a new file
+    Decrypt {
         #[structopt(short, long, parse(from_os_str))]
         certificate: PathBuf,
+        #[structopt(short, long = "in", parse(from_os_str))]
+        input: PathBuf,
+        /// Write to a new file instead of stdout
         #[structopt(short, long, parse(from_os_str))]
-        yaml: PathBuf,
+        out: Option<PathBuf>
+    },
+    /// Edit an encrypted coffer in $EDITOR
+    Edit {
         #[structopt(short, long, parse(from_os_str))]
-        out: PathBuf
+        certificate: PathBuf,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf
     },
+    /// Show the public key of a certificate
     Info {
+        /// Also show the secret key
+        #[structopt(long)]
+        show_secret: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf
+    },
+    /// Write the public key of a certificate to a file, usable for encrypting
+    ExportPublic {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short, long, parse(from_os_str))]
+        out: PathBuf
+    },
+    /// Generate a signing key for coffers
+    SigningKey {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Also write the public key, for trusted signers of coffer-server
+        #[structopt(long, parse(from_os_str))]
+        export_public: Option<PathBuf>
+    },
+    /// Sign or cosign encrypted coffers, without decrypting them
+    #[structopt(visible_alias = "cosign")]
+    Sign {
+        #[structopt(short = "k", long, parse(from_os_str))]
+        signing_key: PathBuf,
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Verify encrypted coffers are signed like coffer-server requires
+    Verify {
+        /// Signing keys or their exported public keys
+        #[structopt(short, long, parse(from_os_str), required = true, number_of_values = 1)]
+        trusted_signers: Vec<PathBuf>,
+        /// Number of trusted signers that must have signed
+        #[structopt(short, long, default_value = "1")]
+        required_signatures: usize,
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Show the envelope header and signers of encrypted coffers without decrypting them
+    Inspect {
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Validate coffer definitions, exits non-zero if there are problems
+    Validate {
+        /// Server certificate for validating encrypted coffers
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Import the values of a dotenv file into a shard
+    ImportDotenv {
+        /// Plaintext or encrypted coffer to import into. A plaintext coffer is
+        /// rewritten in canonical form, comments are not kept
+        #[structopt(long, parse(from_os_str))]
+        coffer: PathBuf,
+        /// Server certificate for encrypted coffers
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        /// Name or id of the shard
+        #[structopt(long)]
+        shard: String,
+        /// Replace existing values that differ
+        #[structopt(long)]
+        overwrite: bool,
+        #[structopt(parse(from_os_str))]
+        env: PathBuf
+    },
+    /// Report which clients can read which values
+    Audit {
+        /// Server certificate for encrypted coffers
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        #[structopt(long, possible_values = &["table", "json"], default_value = "table")]
+        format: String,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf
+    },
+    /// Print the values of a shard as dotenv, json or shell variables
+    Export {
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: PathBuf,
+        /// Name or id of the shard
+        #[structopt(long)]
+        shard: String,
+        #[structopt(long, possible_values = &["dotenv", "json", "shell"], default_value = "dotenv")]
+        format: String,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf
+    },
+    /// Show the status of a running coffer-server
+    Status {
+        #[structopt(short, long, env = "COFFER_SERVER_ADDRESS", default_value = "127.0.0.1:9187")]
+        server: String,
+        /// Certificate of one of the server's admin keys
+        #[structopt(long, parse(from_os_str))]
+        admin_cert: PathBuf
+    },
+    /// Make a running coffer-server reload its secrets files
+    Reload {
+        #[structopt(short, long, env = "COFFER_SERVER_ADDRESS", default_value = "127.0.0.1:9187")]
+        server: String,
+        /// Certificate of one of the server's admin keys
+        #[structopt(long, parse(from_os_str))]
+        admin_cert: PathBuf
+    },
+    /// Set a value in an encrypted coffer
+    Set {
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: PathBuf,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Shard name or id
+        shard: String,
+        key: String,
+        #[structopt(required_unless_one = &["stdin", "file"], conflicts_with_all = &["stdin", "file"])]
+        value: Option<String>,
+        /// Read the value from stdin
+        #[structopt(long, conflicts_with = "file")]
+        stdin: bool,
+        /// Read the value from a file
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// Parse the value as toml value instead of taking it as string
+        #[structopt(long)]
+        toml: bool
+    },
+    /// Print a value of an encrypted coffer
+    Get {
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: PathBuf,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Shard name or id
+        shard: String,
+        key: String
+    },
+    /// Remove a value from an encrypted coffer
+    Unset {
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: PathBuf,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Shard name or id
+        shard: String,
+        key: String
+    },
+    /// Re-encrypt coffers for a new server certificate
+    Rekey {
+        /// Current server certificate
+        #[structopt(long, parse(from_os_str))]
+        from: PathBuf,
+        /// New server certificate
+        #[structopt(long, parse(from_os_str))]
+        to: PathBuf,
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Generate values declared in a coffer file and write them into the file
+    ///
+    /// The file is written in canonical form, comments are not kept.
+    Materialize {
+        /// Server certificate for materializing an encrypted coffer
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf
+    },
+    /// List values expiring within the given number of days
+    Expiring {
+        #[structopt(short, long, default_value = "30")]
+        days: i64,
+        /// Server certificate for reading encrypted coffers
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>
+    },
+    /// Show added, removed and changed shards and keys, exits 1 if the coffers differ
+    Diff {
+        /// Server certificate for reading encrypted coffers
+        #[structopt(short, long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        /// Show values instead of masking them
+        #[structopt(long)]
+        show_values: bool,
+        /// Print a single coffer in redacted canonical form, for `git diff`
+        #[structopt(long)]
+        textconv: bool,
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str), required_unless = "textconv")]
+        new: Option<PathBuf>
+    },
+    /// Same as `diff --textconv` with the certificate from the environment, as git textconv
+    GitTextconv {
+        #[structopt(short, long, parse(from_os_str), env = "COFFER_SERVER_CERTIFICATE", hide_env_values = true)]
+        certificate: PathBuf,
         #[structopt(parse(from_os_str))]
         path: PathBuf
+    },
+    /// Three-way merge encrypted coffers, as git merge driver
+    GitMerge {
+        #[structopt(short, long, parse(from_os_str), env = "COFFER_SERVER_CERTIFICATE", hide_env_values = true)]
+        certificate: PathBuf,
+        #[structopt(parse(from_os_str))]
+        base: PathBuf,
+        /// Our version, replaced by the merge result
+        #[structopt(parse(from_os_str))]
+        ours: PathBuf,
+        #[structopt(parse(from_os_str))]
+        theirs: PathBuf,
+        /// Path of the merged file in the repository, for messages
+        path: Option<String>
     }
 }
 
@@ -28,14 +309,146 @@ fn main() {
     let args: Args = Args::from_args();
 
     match args {
-        Args::Certificate {path} => {
+        Args::Certificate {path: Some(path), ..} => {
             certificate::generate_key(path)
         }
-        Args::Encrypt {certificate, yaml, out} => {
-            encrypt::encrypt_yaml(yaml, out, certificate)
+        Args::Certificate {manifest, out_dir, ..} => {
+            if !certificate::generate_manifest(manifest.unwrap(), out_dir.unwrap()) {
+                std::process::exit(1);
+            }
+        }
+        Args::AddClient {name, coffer, server_cert, out, template} => {
+            if !add_client::add_client(name, coffer, server_cert, out, template) {
+                std::process::exit(1);
+            }
+        }
+        Args::Encrypt {certificate, yaml, out, compress, signing_key} => {
+            encrypt::encrypt_yaml(yaml, out, certificate, compress, signing_key)
         }
-        Args::Info {path} => {
-            certificate::info(path)
+        Args::Decrypt {certificate, input, out} => {
+            if !decrypt::decrypt(input, out, certificate) {
+                std::process::exit(1);
+            }
         }
+        Args::Edit {certificate, path} => {
+            if !edit::edit(path, certificate) {
+                std::process::exit(1);
+            }
+        }
+        Args::Info {path, show_secret} => {
+            certificate::info(path, show_secret)
+        }
+        Args::ExportPublic {path, out} => {
+            certificate::export_public(path, out)
+        }
+        Args::SigningKey {path, export_public} => {
+            certificate::generate_signing_key(path, export_public)
+        }
+        Args::Sign {signing_key, paths} => {
+            if !sign::sign(paths, signing_key) {
+                std::process::exit(1);
+            }
+        }
+        Args::Verify {trusted_signers, required_signatures, paths} => {
+            if !sign::verify(paths, trusted_signers, required_signatures) {
+                std::process::exit(1);
+            }
+        }
+        Args::Inspect {paths} => {
+            if !inspect::inspect(paths) {
+                std::process::exit(1);
+            }
+        }
+        Args::Validate {certificate, paths} => {
+            if !validate::validate(paths, certificate) {
+                std::process::exit(1);
+            }
+        }
+        Args::ImportDotenv {coffer, certificate, shard, overwrite, env} => {
+            if !import::import_dotenv(coffer, certificate, shard, env, overwrite) {
+                std::process::exit(1);
+            }
+        }
+        Args::Audit {certificate, format, path} => {
+            if !audit::audit(path, certificate, format == "json") {
+                std::process::exit(1);
+            }
+        }
+        Args::Export {certificate, shard, format, path} => {
+            if !export::export(path, certificate, shard, format) {
+                std::process::exit(1);
+            }
+        }
+        Args::Status {server, admin_cert} => {
+            if !status::status(server, admin_cert) {
+                std::process::exit(1);
+            }
+        }
+        Args::Reload {server, admin_cert} => {
+            if !status::reload(server, admin_cert) {
+                std::process::exit(1);
+            }
+        }
+        Args::Set {certificate, path, shard, key, value, stdin, file, toml} => {
+            let input = match (value, file) {
+                (Some(value), _) => secret::Input::Arg(value),
+                (None, Some(file)) => secret::Input::File(file),
+                (None, None) if stdin => secret::Input::Stdin,
+                (None, None) => unreachable!{}
+            };
+            if !secret::set(path, certificate, shard, key, input, toml) {
+                std::process::exit(1);
+            }
+        }
+        Args::Get {certificate, path, shard, key} => {
+            if !secret::get(path, certificate, shard, key) {
+                std::process::exit(1);
+            }
+        }
+        Args::Unset {certificate, path, shard, key} => {
+            if !secret::unset(path, certificate, shard, key) {
+                std::process::exit(1);
+            }
+        }
+        Args::Rekey {from, to, paths} => {
+            if !rekey::rekey(paths, from, to) {
+                std::process::exit(1);
+            }
+        }
+        Args::Materialize {certificate, path} => {
+            if !materialize::materialize(path, certificate) {
+                std::process::exit(1);
+            }
+        }
+        Args::Expiring {days, certificate, paths} => {
+            if !expiring::expiring(paths, days, certificate) {
+                std::process::exit(1);
+            }
+        }
+        Args::Diff {certificate, show_values, textconv: true, old, ..} => {
+            textconv(old, certificate, show_values)
+        }
+        Args::Diff {certificate, show_values, old, new, ..} => {
+            match diff::diff(old, new.unwrap(), certificate, show_values) {
+                Some(false) => (),
+                Some(true) => std::process::exit(1),
+                None => std::process::exit(2)
+            }
+        }
+        Args::GitTextconv {certificate, path} => {
+            textconv(path, Some(certificate), false)
+        }
+        Args::GitMerge {certificate, base, ours, theirs, path} => {
+            if !git::merge(base, ours, theirs, path, Some(certificate)) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Print `path` for `git diff`, exits 2 like `diff` if it could not be read
+fn textconv(path: PathBuf, certificate: Option<PathBuf>, show_values: bool) {
+    if !diff::textconv(path, certificate, show_values) {
+        std::process::exit(2);
     }
 }
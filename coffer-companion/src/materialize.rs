@@ -0,0 +1,60 @@
+//! Materializing generated values in coffer definitions
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferError, CofferResult};
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::generate;
+
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Materialize generated values in the coffer file at `path` in place
+///
+/// Encrypted coffers are opened and sealed again with the server
+/// `certificate`. The file is rewritten in canonical form, without comments.
+/// Returns `false` if the coffer could not be materialized.
+pub fn materialize(path: PathBuf, certificate: Option<PathBuf>) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    match materialize_file(&path, certificate.as_ref()) {
+        Ok(0) => println!{"{}: No values to generate", path.display()},
+        Ok(count) => println!{"{}: Generated {} values", path.display(), count},
+        Err(problem) => { println!{"{}: {}", path.display(), problem}; return false }
+    }
+
+    true
+}
+
+fn materialize_file(path: &Path, certificate: Option<&Certificate>) -> Result<usize, String> {
+    let toml = coffer_file::read(path, certificate)?;
+
+    match materialize_toml(&toml).map_err(|err| err.to_string())? {
+        Some((toml, count)) => coffer_file::write(path, &toml, certificate).map(|_| count),
+        None => Ok(0)
+    }
+}
+
+/// Materialize generated values in a toml coffer definition
+///
+/// Returns the coffer in canonical form and the number of generated values, or
+/// `None` if there is nothing to generate.
+pub fn materialize_toml(toml: &str) -> CofferResult<Option<(String, usize)>> {
+    let mut document = match toml.parse()? {
+        toml::Value::Table(t) => t,
+        _ => return Err(CofferError::Msg("Invalid secrets file"))
+    };
+
+    let count = generate::materialize(&mut document)?;
+    if count == 0 {
+        return Ok(None);
+    }
+
+    let mut coffer = CofferMap::new();
+    coffer.from_toml_table("", &document)?;
+
+    Ok(Some((coffer.to_toml(), count)))
+}
@@ -0,0 +1,57 @@
+//! Re-encryption of coffers for a new server certificate
+
+use coffer_common::certificate::{Certificate, PublicCertificate};
+use coffer_common::envelope;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Re-encrypt the coffers in `paths` from the `from` to the `to` certificate
+///
+/// Other recipients and the compression of a coffer are kept. Each file is replaced atomically,
+/// only after verifying it decrypts with the new certificate. Returns `false` if any file could not be re-encrypted.
+pub fn rekey(paths: Vec<PathBuf>, from: PathBuf, to: PathBuf) -> bool {
+    let (from, to) = match (Certificate::new_from_cbor(&from), Certificate::new_from_cbor(&to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) => { eprintln!{"Could not read certificate {}: {:?}", from.display(), err}; return false }
+        (_, Err(err)) => { eprintln!{"Could not read certificate {}: {:?}", to.display(), err}; return false }
+    };
+    let mut ok = true;
+
+    for path in &paths {
+        match rekey_file(path, &from, &to) {
+            Ok(()) => println!{"{}: Re-encrypted", path.display()},
+            Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; ok = false }
+        }
+    }
+
+    ok
+}
+
+fn rekey_file(path: &Path, from: &Certificate, to: &Certificate) -> Result<(), String> {
+    let sealed = fs::read(path)
+        .map_err(|err| format!{"Could not read file: {}", err})?;
+    let plaintext = envelope::open(&sealed, from)
+        .map_err(|err| format!{"Could not decrypt with old certificate: {}", err})?;
+
+    let mut recipients: Vec<PublicCertificate> = coffer_file::recipients(path).into_iter()
+        .filter(|r| r.public_key() != from.public_key() && r.public_key() != to.public_key())
+        .collect();
+    recipients.push(to.public_certificate());
+
+    coffer_file::warn_signatures(path);
+    let resealed = coffer_file::seal(&plaintext, &recipients, coffer_file::compressed(path), None)?;
+    if envelope::open(&resealed, to).ok().as_ref() != Some(&plaintext) {
+        return Err("Re-encrypted coffer does not decrypt with new certificate".to_string());
+    }
+
+    coffer_file::write_atomic(path, &resealed)?;
+
+    // verify what actually ended up on disk
+    match fs::read(path).ok().and_then(|written| envelope::open(&written, to).ok()) {
+        Some(written) if written == plaintext => Ok(()),
+        _ => Err("Written coffer does not decrypt with new certificate".to_string())
+    }
+}
@@ -0,0 +1,153 @@
+//! Reading and changing single values of encrypted coffers
+//!
+//! Coffers are only decrypted in memory, plaintext is never written to disk.
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{Coffer, CofferKey, CofferMeta, CofferValue, RESERVED_KEYS};
+use coffer_common::coffer_map::CofferMap;
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Where to read a value from
+pub enum Input {
+    Arg(String),
+    Stdin,
+    File(PathBuf)
+}
+
+/// Set `key` in `shard` of the encrypted coffer at `path`
+///
+/// The value is a string, or a toml value if `toml` is set. A toml value can
+/// carry [metadata](coffer_common::coffer#metadata), otherwise existing
+/// metadata is kept. Returns `false` if the value could not be set.
+pub fn set(path: PathBuf, certificate: PathBuf, shard: String, key: String, input: Input, toml: bool) -> bool {
+    let result = value_key(&key)
+        .and_then(|_| read_input(input))
+        .and_then(|value| parse(&value, toml))
+        .and_then(|(value, meta)| change(&path, &certificate, &shard, |coffer, id| {
+            let key = CofferKey{shard: id.to_string(), key: key.clone()};
+            coffer.push(key.clone(), value);
+            if let Some(meta) = meta {
+                coffer.put_meta(key, meta);
+            }
+            Ok(())
+        }));
+
+    report(&path, result)
+}
+
+/// Remove `key` from `shard` of the encrypted coffer at `path`
+///
+/// Returns `false` if there is no such value.
+pub fn unset(path: PathBuf, certificate: PathBuf, shard: String, key: String) -> bool {
+    let result = value_key(&key).and_then(|_| change(&path, &certificate, &shard, |coffer, id| {
+        coffer.remove(&CofferKey{shard: id.to_string(), key: key.clone()})
+            .map(|_| ())
+            .ok_or_else(|| format!{"No value {}.{}", shard, key})
+    }));
+
+    report(&path, result)
+}
+
+/// Print `key` of `shard` in the encrypted coffer at `path`
+///
+/// Returns `false` if there is no such value.
+pub fn get(path: PathBuf, certificate: PathBuf, shard: String, key: String) -> bool {
+    let certificate = match Certificate::new_from_cbor(certificate) {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let value = open(&path, &certificate).and_then(|coffer| {
+        let id = shard_id(&coffer, &shard)?;
+        coffer.get(&CofferKey{shard: id, key: key.clone()})
+            .ok_or_else(|| format!{"No value {}.{}", shard, key})
+    });
+
+    match value {
+        Ok(value) => { println!{"{}", value}; true }
+        Err(problem) => report(&path, Err(problem))
+    }
+}
+
+/// Fail for keys that are not values, like the shard `id`
+pub fn value_key(key: &str) -> Result<(), String> {
+    if RESERVED_KEYS.contains(&key) {
+        return Err(format!{"{} is reserved and not a value, edit the coffer to change it", key});
+    }
+
+    Ok(())
+}
+
+fn report(path: &Path, result: Result<(), String>) -> bool {
+    result.map_err(|problem| eprintln!{"{}: {}", path.display(), problem}).is_ok()
+}
+
+fn read_input(input: Input) -> Result<String, String> {
+    match input {
+        Input::Arg(value) => Ok(value),
+        Input::Stdin => {
+            let mut value = String::new();
+            io::stdin().read_to_string(&mut value)
+                .map_err(|err| format!{"Could not read value from stdin: {}", err})?;
+            // a single trailing newline is from `echo` or the terminal
+            if value.ends_with('\n') { value.pop(); }
+            Ok(value)
+        }
+        Input::File(file) => fs::read_to_string(&file)
+            .map_err(|err| format!{"Could not read value from {}: {}", file.display(), err})
+    }
+}
+
+/// Parse a value given on the command line
+fn parse(value: &str, toml: bool) -> Result<(CofferValue, Option<CofferMeta>), String> {
+    if !toml {
+        return Ok((CofferValue::String(value.to_string()), None));
+    }
+
+    // read like a value in a coffer file
+    let mut parsed = CofferMap::new();
+    parsed.put_toml(&format!{"[shard]\nid = \"0\"\nvalue = {}\n", value})
+        .map_err(|err| format!{"Invalid value: {}", err})?;
+
+    let key = CofferKey{shard: "0".into(), key: "value".into()};
+    Ok((parsed.get(&key).unwrap(), parsed.get_meta(&key)))
+}
+
+fn open(path: &Path, certificate: &Certificate) -> Result<CofferMap, String> {
+    let toml = coffer_file::read(path, Some(certificate))?;
+
+    let mut coffer = CofferMap::new();
+    coffer.put_toml(&toml).map_err(|err| err.to_string())?;
+
+    Ok(coffer)
+}
+
+/// The id of the shard named `shard`, `shard` can also be an id itself
+pub fn shard_id(coffer: &CofferMap, shard: &str) -> Result<String, String> {
+    let ids = coffer.get_shard_ids();
+
+    ids.iter()
+        .find(|id| coffer.get_shard_name(id).as_deref() == Some(shard))
+        .or_else(|| ids.iter().find(|id| *id == shard))
+        .cloned()
+        .ok_or_else(|| format!{"No shard {}", shard})
+}
+
+/// Apply `change` to `shard` of the encrypted coffer at `path` and seal it again
+fn change<F>(path: &Path, certificate: &Path, shard: &str, change: F) -> Result<(), String>
+where F: FnOnce(&mut CofferMap, &str) -> Result<(), String>
+{
+    let certificate = Certificate::new_from_cbor(certificate)
+        .map_err(|err| format!{"Could not read server certificate: {:?}", err})?;
+
+    let mut coffer = open(path, &certificate)?;
+    let id = shard_id(&coffer, shard)?;
+    change(&mut coffer, &id)?;
+
+    coffer_file::write(path, &coffer.to_toml(), Some(&certificate))
+}
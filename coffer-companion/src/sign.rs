@@ -0,0 +1,76 @@
+//! Signing of encrypted coffers
+//!
+//! For two-person rules, coffer-server can require signatures by several
+//! maintainers. Maintainers cosign a coffer without being able to decrypt it.
+
+use coffer_common::certificate::fingerprint;
+use coffer_common::envelope::{self, Envelope};
+use coffer_common::signing::{PublicSigningKey, SigningKey};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Sign the encrypted coffers in `paths` with the signing `key`
+///
+/// Coffers are not decrypted, signing only needs the encrypted file. Returns
+/// `false` if any coffer could not be signed.
+pub fn sign(paths: Vec<PathBuf>, key: PathBuf) -> bool {
+    let key = match SigningKey::new_from_cbor(key) {
+        Ok(key) => key,
+        Err(err) => { eprintln!{"Could not read signing key: {:?}", err}; return false }
+    };
+    let mut ok = true;
+
+    for path in &paths {
+        match sign_file(path, &key) {
+            Ok(()) => println!{"{}: Signed by {}", path.display(), fingerprint(&key.public_key())},
+            Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; ok = false }
+        }
+    }
+
+    ok
+}
+
+/// Verify the encrypted coffers in `paths` like coffer-server
+///
+/// Each coffer must be signed by at least `required` of the `trusted` signers.
+/// Returns `false` if any coffer is not.
+pub fn verify(paths: Vec<PathBuf>, trusted: Vec<PathBuf>, required: usize) -> bool {
+    let trusted: Vec<PublicSigningKey> = match trusted.iter().map(PublicSigningKey::new_from_cbor).collect() {
+        Ok(trusted) => trusted,
+        Err(err) => { eprintln!{"Could not read trusted signer: {:?}", err}; return false }
+    };
+    let mut ok = true;
+
+    for path in &paths {
+        let verified = fs::read(path)
+            .map_err(|err| format!{"Could not read file: {}", err})
+            .and_then(|content| envelope::verify(&content, &trusted, required).map_err(|err| err.to_string()));
+
+        match verified {
+            Ok(()) => println!{"{}: Verified", path.display()},
+            Err(problem) => { eprintln!{"{}: {}", path.display(), problem}; ok = false }
+        }
+    }
+
+    ok
+}
+
+fn sign_file(path: &Path, key: &SigningKey) -> Result<(), String> {
+    let content = fs::read(path)
+        .map_err(|err| format!{"Could not read file: {}", err})?;
+
+    if !Envelope::is_envelope(&content) {
+        return Err("Coffer has no envelope header and can't be signed, encrypt it again".to_string());
+    }
+    let mut envelope = Envelope::from_bytes(&content)
+        .map_err(|err| err.to_string())?;
+
+    envelope.sign(key);
+
+    let signed = envelope.to_bytes()
+        .map_err(|err| err.to_string())?;
+    coffer_file::write_atomic(path, &signed)
+}
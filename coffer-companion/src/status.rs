@@ -0,0 +1,153 @@
+//! Status and reloading of a running coffer-server
+
+use coffer_common::certificate::Certificate;
+use coffer_common::date::Date;
+use coffer_common::status::{ReloadStatus, ServerStatus};
+
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+/// Largest response accepted from the server, statuses and challenges are small
+const MAX_RESPONSE: u64 = 4 * 1024 * 1024;
+
+/// Request and print the status of the coffer-server at `server`
+///
+/// The `admin` certificate must be one of the server's admin keys. Returns
+/// `false` if the status could not be retrieved.
+pub fn status(server: String, admin: PathBuf) -> bool {
+    let admin = match Certificate::new_from_cbor(admin) {
+        Ok(admin) => admin,
+        Err(err) => { eprintln!{"Could not read admin certificate: {:?}", err}; return false }
+    };
+
+    let status = match request(&server, &admin) {
+        Ok(status) => status,
+        Err(problem) => { eprintln!{"{}: {}", server, problem}; return false }
+    };
+
+    println!{"Version: {}", status.version};
+    println!{"Uptime: {}", duration(status.uptime)};
+    println!{"Secrets loaded: {}", datetime(status.loaded)};
+    println!{"Shards: {}", status.shards};
+    println!{"Connections: {}", status.connections};
+    println!{"Clients:"};
+
+    let mut clients = status.clients;
+    clients.sort();
+    let width = clients.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, fingerprint) in clients {
+        println!{"  {:width$}  {}", name, fingerprint, width = width};
+    }
+
+    true
+}
+
+/// Make the coffer-server at `server` reload its secrets files
+///
+/// The server keeps its current secrets if the reload fails. Returns `false`
+/// if the secrets were not reloaded.
+pub fn reload(server: String, admin: PathBuf) -> bool {
+    let admin = match Certificate::new_from_cbor(admin) {
+        Ok(admin) => admin,
+        Err(err) => { eprintln!{"Could not read admin certificate: {:?}", err}; return false }
+    };
+
+    let reloaded = connect(&server, &admin).and_then(|mut stream| {
+        // the server only reloads after the challenge sealed for the admin
+        // is answered
+        send(&mut stream, 0x12, &[])?;
+        let challenge = receive(&mut stream, &admin, 0x14)?;
+        send(&mut stream, 0x15, &challenge)?;
+
+        let status = receive(&mut stream, &admin, 0x13)?;
+        bye(&mut stream);
+
+        serde_cbor::from_slice::<ReloadStatus>(&status)
+            .map_err(|err| format!{"Invalid response: {}", err})
+    });
+
+    match reloaded {
+        Ok(ReloadStatus {error: None}) => { println!{"Reloaded secrets"}; true }
+        Ok(ReloadStatus {error: Some(err)}) => {
+            eprintln!{"{}: Secrets not reloaded: {}", server, err};
+            false
+        }
+        Err(problem) => { eprintln!{"{}: {}", server, problem}; false }
+    }
+}
+
+fn request(server: &str, admin: &Certificate) -> Result<ServerStatus, String> {
+    let mut stream = connect(server, admin)?;
+
+    send(&mut stream, 0x10, &[])?;
+    let status = receive(&mut stream, admin, 0x11)?;
+    bye(&mut stream);
+
+    serde_cbor::from_slice(&status)
+        .map_err(|err| format!{"Invalid status: {}", err})
+}
+
+/// Connect to `server` and say hello with the public key of `admin`
+fn connect(server: &str, admin: &Certificate) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(server)
+        .map_err(|err| format!{"Could not connect: {}", err})?;
+
+    send(&mut stream, 0x00, &admin.public_key())?;
+    Ok(stream)
+}
+
+fn send(stream: &mut TcpStream, msg_type: u8, data: &[u8]) -> Result<(), String> {
+    stream.write_all(&framed(msg_type, data))
+        .map_err(|err| format!{"Could not send request: {}", err})
+}
+
+/// Receive a message of `msg_type` sealed for `admin` and open it
+fn receive(stream: &mut TcpStream, admin: &Certificate, msg_type: u8) -> Result<Vec<u8>, String> {
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header)
+        .map_err(|_| "No response, is the certificate an admin key of the server?".to_string())?;
+
+    let size = u64::from_be_bytes(header[0..8].try_into().unwrap());
+    if header[8] != msg_type {
+        return Err(format!{"Unexpected response type {}", header[8]});
+    }
+
+    if size > MAX_RESPONSE {
+        return Err(format!{"Response of {} bytes is too large", size});
+    }
+
+    let mut message = vec![0u8; size as usize];
+    stream.read_exact(&mut message)
+        .map_err(|err| format!{"Could not read response: {}", err})?;
+
+    admin.open(&message)
+        .map_err(|_| "Could not decrypt response".to_string())
+}
+
+fn bye(stream: &mut TcpStream) {
+    let _ = stream.write_all(&framed(0x99, &[]));
+}
+
+fn framed(msg_type: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 9);
+    frame.extend(&(data.len() as u64).to_be_bytes());
+    frame.push(msg_type);
+    frame.extend(data);
+
+    frame
+}
+
+/// Format seconds since the unix epoch as UTC date and time
+fn datetime(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    format!{"{} {:02}:{:02}:{:02} UTC", Date::from_days(days as i64), secs / 3600, secs / 60 % 60, secs % 60}
+}
+
+fn duration(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time = format!{"{}h {}m {}s", secs / 3600, secs / 60 % 60, secs % 60};
+
+    if days > 0 { format!{"{}d {}", days, time} } else { time }
+}
@@ -0,0 +1,129 @@
+//! Validation of coffer definitions
+
+use coffer_common::certificate::Certificate;
+use coffer_common::coffer::{self, Coffer, CofferError};
+use coffer_common::coffer_map::CofferMap;
+use coffer_common::keyring::{self, KeyringError};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::coffer_file;
+
+/// Validate the coffer definitions in `paths`
+///
+/// Like in coffer-server, directories are expanded to the coffer files in them
+/// and all definitions are merged into a single coffer. Encrypted coffers are
+/// opened with the server `certificate`. Every problem found is printed.
+/// Returns `false` if there are any problems.
+pub fn validate(paths: Vec<PathBuf>, certificate: Option<PathBuf>) -> bool {
+    let certificate = match certificate.map(Certificate::new_from_cbor).transpose() {
+        Ok(certificate) => certificate,
+        Err(err) => { eprintln!{"Could not read server certificate: {:?}", err}; return false }
+    };
+
+    let paths = match coffer::coffer_files(&paths) {
+        Ok(paths) => paths,
+        Err((path, err)) => { println!{"{}: {}", path.display(), err}; return false }
+    };
+
+    let problems = problems(&paths, certificate.as_ref());
+    for (path, problem) in &problems {
+        println!{"{}: {}", path.display(), problem};
+    }
+
+    problems.is_empty()
+}
+
+/// Find all problems in the merged coffer definitions in `paths`
+fn problems<'a>(paths: &'a [PathBuf], certificate: Option<&Certificate>) -> Vec<(&'a Path, String)> {
+    // same parsing as in coffer-server
+    let mut coffer = CofferMap::new();
+    let mut problems = Vec::new();
+
+    // the file a shard is defined in, by shard id
+    let mut origins: HashMap<String, &Path> = HashMap::new();
+
+    for path in paths {
+        let toml = match coffer_file::read(path, certificate) {
+            Ok(toml) => toml,
+            Err(problem) => { problems.push((path.as_path(), problem)); continue }
+        };
+
+        if let Err(err) = coffer.put_toml(&toml) {
+            problems.extend(errors(err).iter().map(|e| (path.as_path(), e.to_string())));
+        }
+
+        for id in coffer.get_shard_ids() {
+            origins.entry(id).or_insert(path);
+        }
+    }
+
+    let mut ids = coffer.get_shard_ids();
+    ids.sort();
+
+    let names: Vec<(String, &Path)> = ids.iter()
+        .filter_map(|id| coffer.get_shard_name(id).map(|name| (name, origins[id])))
+        .collect();
+
+    if let Err(err) = coffer.resolve_references() {
+        for error in errors(err) {
+            // reference errors are located by shard name, find its file
+            let location = match &error {
+                CofferError::Reference(location, _) => location.as_str(),
+                _ => ""
+            };
+            let origin = names.iter()
+                .filter(|(name, _)| location == name || location.starts_with(&format!{"{}.", name}))
+                .max_by_key(|(name, _)| name.len())
+                .map_or(paths[0].as_path(), |(_, path)| path);
+            problems.push((origin, error.to_string()));
+        }
+    }
+
+    for id in ids {
+        let name = coffer.get_shard_name(&id).unwrap_or_else(|| id.clone());
+        let origin = origins[&id];
+
+        match keyring::decode_public_key(&id) {
+            Err(KeyringError::HexDecodeError(_)) =>
+                problems.push((origin, format!{"Invalid id of {}: not a hex encoded key", name})),
+            Err(_) =>
+                problems.push((origin, format!{"Invalid id of {}: not a 32 byte public key", name})),
+            Ok(_) if id != id.to_uppercase() =>
+                problems.push((origin, format!{"Invalid id of {}: hex must be upper case", name})),
+            Ok(_) => ()
+        }
+
+        let mut keys: Vec<String> = coffer.get_shard(&id)
+            .map(|shard| shard.0.into_iter().map(|(key, _)| key).collect())
+            .unwrap_or_default();
+        keys.sort();
+
+        for key in keys.iter().filter(|key| !is_env_name(key)) {
+            let problem = format!{"Invalid key {}.{}: not a valid environment variable name", name, key};
+            problems.push((origin, problem));
+        }
+    }
+
+    problems
+}
+
+fn errors(err: CofferError) -> Vec<CofferError> {
+    match err {
+        CofferError::Errors(errs) => errs,
+        err => vec![err]
+    }
+}
+
+/// Whether `key` can be used as environment variable name by coffer-client
+fn is_env_name(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -4,19 +4,17 @@ use log::{debug, error, info, trace, warn};
 use env_logger;
 
 use std::path::PathBuf;
-use std::fs::File;
-use std::io::{Read};
 use structopt::StructOpt;
 
+use coffer_common::certificate::PublicCertificate;
 use coffer_common::keyring::Keyring;
-use coffer_common::coffer::Coffer;
+use coffer_common::signing::PublicSigningKey;
 
 mod server;
-mod coffer_map;
 mod protocol;
+mod secrets;
 
-use server::Server;
-use coffer_map::CofferMap;
+use server::{Admin, Server, State};
 
 #[derive(StructOpt, Debug)]
 struct Args {
@@ -24,10 +22,26 @@ struct Args {
     #[structopt(short, long, parse(from_os_str), env = "COFFER_SERVER_CERTIFICATE", hide_env_values = true)]
     certificate: PathBuf,
 
-    /// Path to secrets file. Will be deleted after processing.
-    /// Must be sealed by the public key of the server certificate
-    #[structopt(short, long, parse(from_os_str), env = "COFFER_SERVER_SECRETS", hide_env_values = true)]
-    secrets: PathBuf,
+    /// Paths to secrets files or directories of secrets files. Must be sealed by
+    /// the public key of the server certificate. Read again on SIGHUP or an
+    /// admin reload request
+    #[structopt(short, long, parse(from_os_str), env = "COFFER_SERVER_SECRETS", hide_env_values = true,
+                required = true, min_values = 1, use_delimiter = true)]
+    secrets: Vec<PathBuf>,
+
+    /// Signing keys or their exported public keys. If given, only secrets
+    /// files signed by the required number of them are loaded
+    #[structopt(long, parse(from_os_str), env = "COFFER_SERVER_TRUSTED_SIGNERS", use_delimiter = true)]
+    trusted_signers: Vec<PathBuf>,
+
+    /// Number of trusted signers that must have signed each secrets file
+    #[structopt(long, env = "COFFER_SERVER_REQUIRED_SIGNATURES", default_value = "1")]
+    required_signatures: usize,
+
+    /// Certificates or exported public keys of admins, which can request the
+    /// server status and reload the secrets
+    #[structopt(long, parse(from_os_str), env = "COFFER_SERVER_ADMIN_KEYS", use_delimiter = true)]
+    admin_keys: Vec<PathBuf>,
 
     /// Address, the coffer server should bind to
     #[structopt(short, long, env = "COFFER_SERVER_ADDRESS", default_value = "127.0.0.1:9187")]
@@ -44,22 +58,54 @@ async fn main() {
     _print_banner();
 
     // create keyring from server certificate
-    let mut keyring = Keyring::new_from_path(&args.certificate);
-
-    // decrypt secrets file and put into coffer
-    let mut secrets_file = File::open(&args.secrets).unwrap();
-    let mut secrets_buf = Vec::new();
-    secrets_file.read_to_end(&mut secrets_buf).unwrap();
-    let secrets_buf_clear = String::from_utf8(keyring.open(&secrets_buf).unwrap()).unwrap();
-
-    // read known client ids from secrets file
-    keyring.add_known_keys_toml(&secrets_buf_clear).unwrap();
-
-    // read secrets from secrets file
-    let coffer = CofferMap::from_toml(&secrets_buf_clear);
+    let keyring = Keyring::new_from_path(&args.certificate);
+
+    // each key counts once towards the required signatures
+    let mut trusted_signers: Vec<PublicSigningKey> = Vec::new();
+    for path in &args.trusted_signers {
+        let key = PublicSigningKey::new_from_cbor(path)
+            .unwrap_or_else(|err| panic!{"Could not read trusted signer {}: {:?}", path.display(), err});
+
+        if trusted_signers.iter().any(|trusted| trusted.public_key() == key.public_key()) {
+            warn!{"Ignoring duplicate trusted signer {}", path.display()}
+        } else {
+            trusted_signers.push(key);
+        }
+    }
+    if trusted_signers.is_empty() {
+        warn!{"No trusted signers, loading unsigned secrets files"}
+    } else if args.required_signatures == 0 || args.required_signatures > trusted_signers.len() {
+        error!{"Required signatures must be between 1 and the number of trusted signers ({})", trusted_signers.len()}
+        std::process::exit(1);
+    } else {
+        info!{"Requiring {} of {} trusted signatures", args.required_signatures, trusted_signers.len()}
+    }
+
+    // decrypt secrets files and put into coffer, again on every reload
+    let (paths, required) = (args.secrets, args.required_signatures);
+    let load = Box::new(move |keyring: &mut Keyring| {
+        secrets::load(keyring, &paths, &trusted_signers, required)
+            .map_err(|err| err.to_string())
+    });
+    let state = match State::new(keyring, load) {
+        Ok(state) => state,
+        Err(err) => {
+            error!{"{}", err}
+            std::process::exit(1);
+        }
+    };
+
+    for (name, _) in state.get().0.known_keys() {
+        info!{"Serving client {}", name}
+    }
+
+    let admin_keys = args.admin_keys.iter()
+        .map(|path| PublicCertificate::new_from_cbor(path)
+             .unwrap_or_else(|err| panic!{"Could not read admin key {}: {:?}", path.display(), err}))
+        .collect();
 
     // start server
-    let server = Server::new(keyring, coffer);
+    let server = Server::new(state, Admin::new(admin_keys));
     server.run(args.address).await;
 }
 
@@ -11,10 +11,17 @@ use serde_cbor;
 
 use quick_error::quick_error;
 
-use coffer_common::coffer::Coffer;
+use coffer_common::certificate::fingerprint;
+use coffer_common::coffer::{Coffer, CofferKey};
+use coffer_common::date::Date;
 use coffer_common::keyring::Keyring;
+use coffer_common::status::{ReloadStatus, ServerStatus};
+
+use crate::server::{self, Admin};
 
 use hex;
+use sodiumoxide::randombytes::randombytes;
+use sodiumoxide::utils::memcmp;
 
 quick_error! {
     #[derive(Debug)]
@@ -33,6 +40,8 @@ quick_error! {
 enum State {
     Start,
     Link,
+    /// Waiting for the admin to answer the challenge before reloading
+    Challenge(Vec<u8>),
     Bye,
     End
 }
@@ -41,6 +50,9 @@ enum State {
 enum Request {
     Hello(Vec<u8>),
     Get,
+    Status,
+    Reload,
+    Answer(Vec<u8>),
     Bye
 }
 
@@ -50,18 +62,23 @@ where C: Coffer
     stream: TcpStream,
     coffer: Arc<C>,
     keyring: Arc<Keyring>,
+    server: Arc<server::State<C>>,
+    admin: Arc<Admin>,
     client: Option<Vec<u8>>,
     state: State
 }
 
 impl<C> Protocol<C>
-where C: Coffer
+where C: Coffer + Send + Sync + 'static
 {
-    pub fn new(stream: TcpStream, coffer: Arc<C>, keyring: Arc<Keyring>) -> Protocol<C>
+    /// Handle a connection with the keyring and coffer currently loaded by
+    /// `server`
+    pub fn new(stream: TcpStream, server: Arc<server::State<C>>, admin: Arc<Admin>) -> Protocol<C>
     {
         let state = State::Start;
         let client = None;
-        Protocol {stream, coffer, keyring, client, state}
+        let (keyring, coffer) = server.get();
+        Protocol {stream, coffer, keyring, server, admin, client, state}
     }
 
     pub async fn run(mut self)
@@ -94,6 +111,9 @@ where C: Coffer
         match msg_type {
             0x00 => Request::Hello(message),
             0x02 => Request::Get,
+            0x10 => Request::Status,
+            0x12 => Request::Reload,
+            0x15 => Request::Answer(message),
             0x99 => Request::Bye,
             _ => panic!{"Invalid message type {}", msg_type}
         }
@@ -105,6 +125,15 @@ where C: Coffer
             (State::Start, Request::Hello(pk)) => {
                 debug!{"Reading public key"}
                 self.client = Some(pk);
+
+                match self.keyring.get_known_key_name(self.client.as_ref().unwrap()) {
+                    Some(name) => info!{"Client {} connected", name},
+                    None if self.admin.key(self.client.as_ref().unwrap()).is_some() => {
+                        info!{"Admin {} connected", self.client_name()}
+                    }
+                    None => warn!{"Unknown client {} connected", self.client_name()}
+                }
+
                 self.state = State::Link;
             }
 
@@ -112,14 +141,39 @@ where C: Coffer
                 debug!{"Writing response"}
                 let shard_id = hex::encode_upper(self.client.as_ref().unwrap());
 
-                let res = self.coffer
-                    .get_shard(shard_id)
-                    .unwrap();
-
-                let response = self.keyring.seal(
-                        &self.client.as_ref().unwrap(),
-                        &serde_cbor::to_vec(&res).unwrap()
-                    ).unwrap();
+                let mut res = match self.coffer.get_shard(&shard_id) {
+                    Some(res) => res,
+                    None => {
+                        error!{"No shard for client {}", self.client_name()}
+                        self.state = State::End;
+                        return;
+                    }
+                };
+
+                // expired values are not served, resolved references expire
+                // with the values they reference
+                let today = Date::today();
+                let coffer = &self.coffer;
+                let (expired, valid) = res.0.into_iter().partition(|(key, _)| {
+                    coffer.get_meta(&CofferKey{shard: shard_id.clone(), key: key.clone()})
+                        .map_or(false, |meta| meta.is_expired(today))
+                });
+                res.0 = valid;
+
+                for (key, _) in expired {
+                    warn!{"Not sending expired value {} to client {}", key, self.client_name()}
+                }
+
+                let response = match self.keyring.seal(
+                        self.client.as_ref().unwrap(),
+                        &serde_cbor::to_vec(&res).unwrap()) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        error!{"Could not seal shard for client {}: {:?}", self.client_name(), err}
+                        self.state = State::End;
+                        return;
+                    }
+                };
 
                 // TODO magic number
                 let frame = frame::framed(0x05u8, response).await;
@@ -128,6 +182,65 @@ where C: Coffer
                 self.stream.write_all(&frame).await.unwrap();
                 self.stream.flush().await.unwrap();
 
+                info!{"Sent shard to client {}", self.client_name()}
+                self.state = State::Bye;
+            }
+
+            (State::Link, Request::Status) => {
+                if !self.is_admin("status") {
+                    return;
+                }
+
+                let status = ServerStatus {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime: self.admin.uptime(),
+                    shards: self.coffer.get_shard_ids().len(),
+                    clients: self.keyring.known_keys()
+                        .map(|(name, key)| (name.to_string(), fingerprint(key)))
+                        .collect(),
+                    connections: self.admin.connections(),
+                    loaded: self.server.loaded()
+                };
+
+                // TODO magic number
+                self.send_admin(0x11u8, &serde_cbor::to_vec(&status).unwrap()).await;
+
+                info!{"Sent status to admin {}", self.client_name()}
+                self.state = State::Bye;
+            }
+
+            (State::Link, Request::Reload) => {
+                if !self.is_admin("reload") {
+                    return;
+                }
+
+                // anyone can send an admin's public key, only the admin can
+                // open the challenge
+                let challenge = randombytes(32);
+                // TODO magic number
+                self.send_admin(0x14u8, &challenge).await;
+                self.state = State::Challenge(challenge);
+            }
+
+            (State::Challenge(challenge), Request::Answer(answer)) => {
+                if !memcmp(challenge, &answer) {
+                    warn!{"Refusing reload request of {}, wrong challenge answer", self.client_name()}
+                    self.state = State::End;
+                    return;
+                }
+
+                info!{"Reloading secrets for admin {}", self.client_name()}
+                let server = self.server.clone();
+                let reloaded = tokio::task::spawn_blocking(move || server.request_reload()).await
+                    .unwrap_or_else(|err| Err(format!{"Reload failed: {}", err}));
+
+                if let Err(err) = &reloaded {
+                    warn!{"Secrets not reloaded for admin {}: {}", self.client_name(), err}
+                }
+                let status = ReloadStatus { error: reloaded.err() };
+
+                // TODO magic number
+                self.send_admin(0x13u8, &serde_cbor::to_vec(&status).unwrap()).await;
                 self.state = State::Bye;
             }
 
@@ -137,6 +250,39 @@ where C: Coffer
             _ => self.state = State::End
         }
     }
+
+    /// Whether the client is an admin. Ends the connection of other clients
+    /// for their `request`.
+    fn is_admin(&mut self, request: &str) -> bool {
+        if self.admin.key(self.client.as_ref().unwrap()).is_some() {
+            return true;
+        }
+
+        warn!{"Refusing {} request of client {}, not an admin", request, self.client_name()}
+        self.state = State::End;
+        false
+    }
+
+    /// Seal `message` for the admin client and send it
+    async fn send_admin(&mut self, msg_type: u8, message: &[u8]) {
+        let response = self.admin.key(self.client.as_ref().unwrap()).unwrap()
+            .seal(message).unwrap();
+
+        let frame = frame::framed(msg_type, response).await;
+        self.stream.write_all(&frame).await.unwrap();
+        self.stream.flush().await.unwrap();
+    }
+
+    /// Name of the client for logs and errors. Falls back to the hex encoded
+    /// public key for clients unknown to the keyring.
+    fn client_name(&self) -> String {
+        match &self.client {
+            Some(pk) => self.keyring.get_known_key_name(pk)
+                .map(str::to_string)
+                .unwrap_or_else(|| hex::encode_upper(pk)),
+            None => "<no hello>".to_string()
+        }
+    }
 }
 
 mod frame {
@@ -0,0 +1,93 @@
+//! Loading of sealed secrets files into a coffer
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use quick_error::quick_error;
+
+use coffer_common::envelope::{self, EnvelopeError};
+use coffer_common::keyring::{Keyring, KeyringError};
+use coffer_common::signing::PublicSigningKey;
+use coffer_common::coffer::{self, Coffer, CofferError};
+use coffer_common::coffer_map::CofferMap;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SecretsError {
+        Io(path: PathBuf, err: std::io::Error) {
+            display("{}: {}", path.display(), err)
+        }
+        Keyring(path: PathBuf, err: KeyringError) {
+            display("{}: Could not open secrets file: {}", path.display(), err)
+        }
+        Signature(path: PathBuf, err: EnvelopeError) {
+            display("{}: Refusing secrets file: {}", path.display(), err)
+        }
+        Utf8(path: PathBuf) {
+            display("{}: Secrets are not valid UTF-8", path.display())
+        }
+        Coffer(path: PathBuf, err: CofferError) {
+            display("{}: {}", path.display(), err)
+        }
+        References(err: CofferError) {
+            display("{}", err)
+        }
+    }
+}
+
+/// Expand directories in `paths` to the secrets files they contain, like
+/// `coffer::coffer_files`
+pub fn secrets_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, SecretsError> {
+    coffer::coffer_files(paths)
+        .map_err(|(path, err)| SecretsError::Io(path, err))
+}
+
+/// Decrypt a secrets file sealed for the keyring's certificate
+///
+/// If there are `trusted` signers, the file must be signed by at least
+/// `required` of them.
+fn open(keyring: &Keyring, path: &Path, trusted: &[PublicSigningKey], required: usize) -> Result<String, SecretsError> {
+    let secrets_buf = fs::read(path)
+        .map_err(|err| SecretsError::Io(path.to_owned(), err))?;
+
+    if !trusted.is_empty() {
+        envelope::verify(&secrets_buf, trusted, required)
+            .map_err(|err| SecretsError::Signature(path.to_owned(), err))?;
+    }
+    let secrets_buf_clear = keyring.open(&secrets_buf)
+        .map_err(|err| SecretsError::Keyring(path.to_owned(), err))?;
+
+    String::from_utf8(secrets_buf_clear)
+        .map_err(|_| SecretsError::Utf8(path.to_owned()))
+}
+
+/// Read the secrets files in `paths` into a single `CofferMap`
+///
+/// The ids of all shards are added to the `keyring` as known keys. Shards must
+/// only be defined once across all files. If there are `trusted` signers, all
+/// files must be signed by at least `required` of them.
+pub fn load(keyring: &mut Keyring, paths: &[PathBuf], trusted: &[PublicSigningKey], required: usize)
+            -> Result<CofferMap, SecretsError> {
+    let mut coffer = CofferMap::new();
+
+    for path in secrets_files(paths)? {
+        info!{"Reading secrets from {}", path.display()}
+        let secrets = open(keyring, &path, trusted, required)?;
+
+        // read secrets from secrets file
+        coffer.put_toml(&secrets)
+            .map_err(|err| SecretsError::Coffer(path.clone(), err))?;
+
+        // read known client ids from secrets file
+        keyring.add_known_keys_toml(&secrets)
+            .map_err(|err| SecretsError::Keyring(path.clone(), err))?;
+    }
+
+    coffer.resolve_references()
+        .map_err(SecretsError::References)?;
+
+    Ok(coffer)
+}
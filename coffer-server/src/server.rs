@@ -5,13 +5,17 @@ use quick_error::quick_error;
 
 use tokio::net::{TcpListener};
 use tokio::stream::StreamExt;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 use std::net::{ToSocketAddrs, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use coffer_common::keyring::Keyring;
 use coffer_common::coffer::Coffer;
-use coffer_common::certificate::CertificateError;
+use coffer_common::certificate::{CertificateError, PublicCertificate};
 
 use crate::protocol::Protocol;
 
@@ -31,20 +35,152 @@ quick_error! {
     }
 }
 
+/// Loads the secrets, adding their clients to the keyring
+pub type Loader<C> = Box<dyn Fn(&mut Keyring) -> Result<C, String> + Send + Sync>;
+
+/// Minimum time between reloads requested by admins
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The loaded keyring and coffer
+///
+/// Both are replaced together on reload. Connections keep the keyring and
+/// coffer they started with.
+pub struct State<C> {
+    current: RwLock<(Arc<Keyring>, Arc<C>)>,
+    load: Loader<C>,
+    /// Seconds since the unix epoch
+    loaded: AtomicU64,
+    /// Start of the last reload, held while reloading
+    reloaded: Mutex<Option<Instant>>
+}
+
+impl<C> State<C>
+where C: Coffer
+{
+    /// Load the secrets for the certificate of `keyring`
+    pub fn new(mut keyring: Keyring, load: Loader<C>) -> Result<State<C>, String> {
+        let coffer = load(&mut keyring)?;
+
+        Ok(State {
+            current: RwLock::new((Arc::new(keyring), Arc::new(coffer))),
+            load,
+            loaded: AtomicU64::new(now()),
+            reloaded: Mutex::new(None)
+        })
+    }
+
+    /// The current keyring and coffer
+    pub fn get(&self) -> (Arc<Keyring>, Arc<C>) {
+        let current = self.current.read().unwrap();
+        (current.0.clone(), current.1.clone())
+    }
+
+    /// Load the secrets again and swap them in
+    ///
+    /// The certificate is kept from the current keyring. If the secrets can't
+    /// be loaded, the current keyring and coffer stay in place. Reloads run one
+    /// at a time. Blocks on reading and decrypting the secrets.
+    pub fn reload(&self) -> Result<(), String> {
+        let mut reloaded = self.reloaded.lock().unwrap();
+        *reloaded = Some(Instant::now());
+        self.load_current()
+    }
+
+    /// Reload like `reload`, unless the last reload started less than
+    /// `RELOAD_INTERVAL` ago
+    pub fn request_reload(&self) -> Result<(), String> {
+        let mut reloaded = self.reloaded.lock().unwrap();
+        if matches!{*reloaded, Some(last) if last.elapsed() < RELOAD_INTERVAL} {
+            return Err(format!{"Reloaded less than {} seconds ago, try again later", RELOAD_INTERVAL.as_secs()});
+        }
+
+        *reloaded = Some(Instant::now());
+        self.load_current()
+    }
+
+    fn load_current(&self) -> Result<(), String> {
+        let mut keyring = self.get().0.without_known_keys();
+
+        let coffer = (self.load)(&mut keyring).map_err(|err| {
+            error!{"Could not reload secrets, keeping the loaded secrets: {}", err}
+            err
+        })?;
+
+        let clients = keyring.known_keys().count();
+        *self.current.write().unwrap() = (Arc::new(keyring), Arc::new(coffer));
+        self.loaded.store(now(), Ordering::SeqCst);
+
+        info!{"Reloaded secrets, serving {} clients", clients}
+        Ok(())
+    }
+
+    /// Seconds since the unix epoch, when the secrets were last loaded
+    pub fn loaded(&self) -> u64 {
+        self.loaded.load(Ordering::SeqCst)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Admin keys and runtime statistics for status requests
+pub struct Admin {
+    keys: Vec<PublicCertificate>,
+    started: SystemTime,
+    connections: AtomicUsize
+}
+
+impl Admin {
+    pub fn new(keys: Vec<PublicCertificate>) -> Admin {
+        Admin {keys, started: SystemTime::now(), connections: AtomicUsize::new(0)}
+    }
+
+    /// The admin key `public_key` belongs to, if any
+    pub fn key(&self, public_key: &[u8]) -> Option<&PublicCertificate> {
+        self.keys.iter().find(|key| key.public_key() == public_key)
+    }
+
+    /// Seconds since the server started
+    pub fn uptime(&self) -> u64 {
+        self.started.elapsed().map(|uptime| uptime.as_secs()).unwrap_or(0)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts an open connection while alive, also if its handler panics
+struct Connection(Arc<Admin>);
+
+impl Connection {
+    fn new(admin: Arc<Admin>) -> Connection {
+        admin.connections.fetch_add(1, Ordering::SeqCst);
+        Connection(admin)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 pub struct Server<C>
 where C: Coffer
 {
-    keyring: Arc<Keyring>,
-    coffer: Arc<C>
+    state: Arc<State<C>>,
+    admin: Arc<Admin>
 }
 
 impl <C> Server <C>
 where C: Coffer + Send + Sync + 'static
 {
 
-    pub fn new(keyring: Keyring, coffer: C) -> Self {
-        Server { keyring: Arc::new(keyring),
-                 coffer: Arc::new(coffer) }
+    pub fn new(state: State<C>, admin: Admin) -> Self {
+        Server { state: Arc::new(state),
+                 admin: Arc::new(admin) }
     }
 
     pub async fn run<T>(self, addr: T)
@@ -61,6 +197,21 @@ where C: Coffer + Send + Sync + 'static
         let mut listener = TcpListener::bind(socket).await
             .expect("Could not bind to socket");
 
+        #[cfg(unix)]
+        {
+            let state = self.state.clone();
+            let mut hangup = signal(SignalKind::hangup())
+                .expect("Could not listen for SIGHUP");
+
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    info!{"Reloading secrets on SIGHUP"}
+                    let state = state.clone();
+                    let _ = tokio::task::spawn_blocking(move || state.reload()).await;
+                }
+            });
+        }
+
         let server = async move {
             let mut incoming = listener.incoming();
 
@@ -71,13 +222,15 @@ where C: Coffer + Send + Sync + 'static
                     Ok(tcp_stream) => {
                         debug!{"Connection ok\nSpawning off connection handler"}
 
-                        let keyring = self.keyring.clone();
-                        let coffer = self.coffer.clone();
+                        let state = self.state.clone();
+                        let admin = self.admin.clone();
 
-                        let protocol = Protocol::new(tcp_stream, coffer, keyring);
+                        let connection = Connection::new(admin.clone());
+                        let protocol = Protocol::new(tcp_stream, state, admin);
 
                         tokio::spawn(async move {
                             protocol.run().await;
+                            drop(connection);
                         });
 
                     }